@@ -0,0 +1,425 @@
+use crate::{bundle, db};
+use chrono::{DateTime, Datelike, Local, NaiveDate};
+use log::{error, info, warn};
+use rusqlite::{backup::Backup, Connection, DatabaseName, OpenFlags};
+use serde::Deserialize;
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    ffi::OsString,
+    fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+const PAGES_PER_STEP: libc::c_int = 256;
+const STEP_PAUSE: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupConfig {
+    /// Directory for the backups, e.g. on the microSD card.
+    pub dir: PathBuf,
+    /// Number of daily backups to keep.
+    #[serde(default = "default_daily")]
+    pub daily: usize,
+    /// Number of weekly backups to keep, the newest of each week.
+    #[serde(default = "default_weekly")]
+    pub weekly: usize,
+    /// Encrypts the backups as bundles with the passphrase in this file.
+    pub passphrase_file: Option<PathBuf>,
+}
+
+fn default_daily() -> usize {
+    7
+}
+
+fn default_weekly() -> usize {
+    4
+}
+
+fn file_name(date: NaiveDate, encrypted: bool) -> String {
+    let extension = if encrypted { "bundle" } else { "db" };
+    format!("decktime-{}.{extension}", date.format("%Y-%m-%d"))
+}
+
+fn parse_file_name(name: &str) -> Option<NaiveDate> {
+    let name = name.strip_prefix("decktime-")?;
+    let date = name
+        .strip_suffix(".db")
+        .or_else(|| name.strip_suffix(".bundle"))?;
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Copies a database with SQLite's online backup API, so the source may be
+/// in use meanwhile.
+fn copy(src: &Connection, dst: &mut Connection) -> rusqlite::Result<()> {
+    Backup::new(src, dst)?.run_to_completion(PAGES_PER_STEP, STEP_PAUSE, None)
+}
+
+fn check(conn: &Connection) -> Result<(), String> {
+    let err = |err: rusqlite::Error| err.to_string();
+    let check: String = conn
+        .query_row("pragma integrity_check", (), |row| row.get(0))
+        .map_err(err)?;
+    if check != "ok" {
+        return Err(check);
+    }
+    let version: u32 = conn
+        .query_row("pragma user_version", (), |row| row.get(0))
+        .map_err(err)?;
+    if version > db::SCHEMA_VERSION {
+        return Err("written by a newer decktime".to_owned());
+    }
+    conn.query_row("select count(*) from events", (), |row| {
+        row.get::<_, u64>(0)
+    })
+    .map_err(|_| "not a decktime database".to_owned())?;
+    Ok(())
+}
+
+/// Checks that `path` is an intact database this version can read.
+pub fn verify(path: &Path) -> Result<(), String> {
+    Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|err| err.to_string())
+        .and_then(|conn| check(&conn))
+        .map_err(|err| format!("{path:?}: {err}"))
+}
+
+/// Serializes a checked copy of the database.
+pub fn snapshot(conn: &Connection) -> Result<Vec<u8>, String> {
+    let err = |err: rusqlite::Error| err.to_string();
+    let mut copied = Connection::open_in_memory().map_err(err)?;
+    copy(conn, &mut copied).map_err(err)?;
+    check(&copied)?;
+    let data = copied.serialize(DatabaseName::Main).map_err(err)?;
+    Ok(data.to_vec())
+}
+
+/// Writes an encrypted bundle and checks that it decrypts to the snapshot,
+/// it is removed if not.
+fn write_bundle(conn: &Connection, passphrase: &str, path: &Path) -> Result<(), String> {
+    let data = snapshot(conn)?;
+    bundle::write(path, &bundle::seal(&data, passphrase)?)
+        .map_err(|err| format!("{path:?}: {err}"))?;
+    let verified = fs::read(path)
+        .map_err(|err| err.to_string())
+        .and_then(|written| bundle::open(&written, passphrase))
+        .and_then(|written| match written == data {
+            true => Ok(()),
+            false => Err("verification failed".to_owned()),
+        });
+    verified.map_err(|err| {
+        let _ = fs::remove_file(path);
+        format!("{path:?}: {err}")
+    })
+}
+
+/// Dates of the backups to delete, keeping the newest `daily` ones and the
+/// newest one of each of the last `weekly` weeks.
+fn expired(mut dates: Vec<NaiveDate>, daily: usize, weekly: usize) -> Vec<NaiveDate> {
+    dates.sort_unstable_by(|a, b| b.cmp(a));
+    let mut weeks = Vec::new();
+    dates
+        .into_iter()
+        .enumerate()
+        .filter(|&(i, date)| {
+            let newest_of_week = weeks.last() != Some(&date.iso_week());
+            if newest_of_week {
+                weeks.push(date.iso_week());
+            }
+            i >= daily && !(newest_of_week && weeks.len() <= weekly)
+        })
+        .map(|(_, date)| date)
+        .collect()
+}
+
+fn rotate(config: &BackupConfig) -> io::Result<()> {
+    let mut backups: BTreeMap<NaiveDate, Vec<OsString>> = BTreeMap::new();
+    for entry in fs::read_dir(&config.dir)? {
+        let name = entry?.file_name();
+        if let Some(date) = name.to_str().and_then(parse_file_name) {
+            backups.entry(date).or_default().push(name);
+        }
+    }
+    let dates = backups.keys().copied().collect();
+    for date in expired(dates, config.daily, config.weekly) {
+        for name in &backups[&date] {
+            let path = config.dir.join(name);
+            info!("removing old backup {path:?}");
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes today's backup of `conn` into the backup directory, replacing an
+/// earlier one of the same day, and removes the expired ones.
+pub fn run(conn: &Connection, config: &BackupConfig, today: NaiveDate) -> Result<PathBuf, String> {
+    let passphrase = config
+        .passphrase_file
+        .as_deref()
+        .map(|file| bundle::read_passphrase(Some(file), false))
+        .transpose()?;
+    let name = file_name(today, passphrase.is_some());
+    let path = config.dir.join(&name);
+    fs::create_dir_all(&config.dir).map_err(|err| format!("{:?}: {err}", config.dir))?;
+
+    match &passphrase {
+        Some(passphrase) => write_bundle(conn, passphrase, &path)?,
+        None => {
+            let tmp = config.dir.join(format!(".{name}.tmp"));
+            let _ = fs::remove_file(&tmp);
+            Connection::open(&tmp)
+                .and_then(|mut dst| copy(conn, &mut dst))
+                .map_err(|err| format!("{tmp:?}: {err}"))
+                .and_then(|_| verify(&tmp))
+                .and_then(|_| fs::rename(&tmp, &path).map_err(|err| format!("{path:?}: {err}")))
+                .inspect_err(|_| {
+                    let _ = fs::remove_file(&tmp);
+                })?;
+        }
+    }
+
+    if let Err(err) = rotate(config) {
+        warn!("backup rotation error: {err}");
+    }
+    Ok(path)
+}
+
+/// Migrates and checks the restored database `tmp`, then moves it over the
+/// database at `path`, the current one is kept as `<path>.pre-restore`.
+fn install(tmp: &Path, path: &Path) -> Result<Option<PathBuf>, String> {
+    fn err(path: &Path) -> impl Fn(rusqlite::Error) -> String + '_ {
+        move |err| format!("{path:?}: {err}")
+    }
+    // Backups of older versions are migrated right away.
+    db::DeckDB::open(&tmp.to_string_lossy()).map_err(err(tmp))?;
+    verify(tmp)?;
+
+    let saved = if path.exists() {
+        // Copied through SQLite, which rolls back a hot journal of the
+        // current database first.
+        let saved = with_suffix(path, ".pre-restore");
+        let _ = fs::remove_file(&saved);
+        let src = Connection::open(path).map_err(err(path))?;
+        let mut dst = Connection::open(&saved).map_err(err(&saved))?;
+        copy(&src, &mut dst).map_err(err(&saved))?;
+        Some(saved)
+    } else {
+        None
+    };
+
+    fs::rename(tmp, path).map_err(|err| format!("{path:?}: {err}"))?;
+    Ok(saved)
+}
+
+fn restore_tmp(path: &Path) -> PathBuf {
+    let tmp = with_suffix(path, ".restore.tmp");
+    let _ = fs::remove_file(&tmp);
+    tmp
+}
+
+/// Replaces the database at `path` with the backup `file`, the current one
+/// is kept as `<path>.pre-restore`. The daemon must not be running.
+pub fn restore(file: &Path, path: &Path) -> Result<Option<PathBuf>, String> {
+    verify(file)?;
+
+    let tmp = restore_tmp(path);
+    let err = |err: rusqlite::Error| format!("{tmp:?}: {err}");
+    Connection::open_with_flags(file, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .and_then(|src| Connection::open(&tmp).and_then(|mut dst| copy(&src, &mut dst)))
+        .map_err(err)
+        .and_then(|_| install(&tmp, path))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+}
+
+/// Like `restore` for an encrypted bundle, which is decrypted next to the
+/// database first.
+pub fn restore_bundle(
+    file: &Path,
+    passphrase: &str,
+    path: &Path,
+) -> Result<Option<PathBuf>, String> {
+    let bundle = fs::read(file).map_err(|err| format!("{file:?}: {err}"))?;
+    let data = bundle::open(&bundle, passphrase).map_err(|err| format!("{file:?}: {err}"))?;
+
+    let tmp = restore_tmp(path);
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut out| out.write_all(&data))
+        .map_err(|err| format!("{tmp:?}: {err}"))
+        .and_then(|_| install(&tmp, path))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+}
+
+/// Scheduled backups of the database file, which run on a thread with their
+/// own connection so the copy and the key derivation do not stall tracking.
+pub struct Backups {
+    config: Arc<BackupConfig>,
+    db_path: String,
+    running: Option<JoinHandle<()>>,
+}
+
+impl Backups {
+    pub fn build(config: BackupConfig, db_path: &str) -> Backups {
+        Backups {
+            config: Arc::new(config),
+            db_path: db_path.to_owned(),
+            running: None,
+        }
+    }
+
+    /// Whether the backup directory lacks a backup of `today`, e.g. because
+    /// the device was off at the scheduled time.
+    pub fn is_due(&self, today: NaiveDate) -> bool {
+        let newest = fs::read_dir(&self.config.dir).ok().and_then(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().to_str().and_then(parse_file_name))
+                .max()
+        });
+        newest.is_none_or(|date| date < today)
+    }
+
+    /// Starts today's backup unless the previous one is still running.
+    pub fn start(&mut self, now: SystemTime) {
+        if self
+            .running
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+        {
+            warn!("previous backup still running, skipping");
+            return;
+        }
+        let today = DateTime::<Local>::from(now).date_naive();
+        let config = Arc::clone(&self.config);
+        let db_path = self.db_path.clone();
+        let spawned = thread::Builder::new()
+            .name("backup".to_owned())
+            .spawn(move || {
+                let backed_up =
+                    Connection::open_with_flags(&db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                        .map_err(|err| format!("{db_path:?}: {err}"))
+                        .and_then(|conn| run(&conn, &config, today));
+                match backed_up {
+                    Ok(path) => info!("database backed up to {path:?}"),
+                    Err(err) => error!("backup error: {err}"),
+                }
+            });
+        match spawned {
+            Ok(handle) => self.running = Some(handle),
+            Err(err) => error!("unable to start backup: {err}"),
+        }
+    }
+}
+
+pub fn get_backup_func(ref_backups: Rc<RefCell<Backups>>) -> impl FnMut(SystemTime) {
+    move |now| ref_backups.borrow_mut().start(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{DeckDB, UNKNOWN_USER_ID},
+        sessions,
+    };
+    use std::{env, os::unix::fs::PermissionsExt};
+
+    #[test]
+    fn rotation() {
+        let date = |day| NaiveDate::from_ymd_opt(2026, 3, day).unwrap();
+        // Mondays are the 2nd, 9th, 16th and 23rd.
+        let dates = (1..=24).map(date).collect();
+        let mut expired = expired(dates, 3, 3);
+        expired.sort();
+        let kept: Vec<_> = (1..=24)
+            .map(date)
+            .filter(|date| !expired.contains(date))
+            .collect();
+        assert_eq!(kept, vec![date(15), date(22), date(23), date(24)]);
+    }
+
+    #[test]
+    fn backup_restore() {
+        let dir = env::temp_dir().join(format!("decktime-backup-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let config = BackupConfig {
+            dir: dir.clone(),
+            daily: 1,
+            weekly: 0,
+            passphrase_file: None,
+        };
+        let conn = DeckDB::open(":memory:").unwrap();
+        sessions::add_session(&conn, 10, UNKNOWN_USER_ID, 3600, 7200, 86400).unwrap();
+
+        let yesterday = NaiveDate::from_ymd_opt(2026, 3, 1).unwrap();
+        let old = run(&conn, &config, yesterday).unwrap();
+        let today = yesterday.succ_opt().unwrap();
+        let path = run(&conn, &config, today).unwrap();
+        assert_eq!(path, dir.join("decktime-2026-03-02.db"));
+        assert!(!old.exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let target = dir.join("deck.db");
+        DeckDB::open(&target.to_string_lossy()).unwrap();
+        let saved = restore(&path, &target).unwrap();
+        assert_eq!(saved, Some(dir.join("deck.db.pre-restore")));
+        let restored = DeckDB::open(&target.to_string_lossy()).unwrap();
+        assert_eq!(sessions::app_sessions(&restored, 86400).unwrap().len(), 1);
+
+        let passphrase_file = dir.join("passphrase");
+        fs::write(&passphrase_file, "hunter2\n").unwrap();
+        let config = BackupConfig {
+            passphrase_file: Some(passphrase_file),
+            ..config
+        };
+        let path = run(&conn, &config, today.succ_opt().unwrap()).unwrap();
+        assert_eq!(path, dir.join("decktime-2026-03-03.bundle"));
+        assert!(bundle::is_bundle(&path).unwrap());
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert!(restore_bundle(&path, "hunter3", &target).is_err());
+        let target = dir.join("imported.db");
+        assert_eq!(restore_bundle(&path, "hunter2", &target).unwrap(), None);
+        let restored = DeckDB::open(&target.to_string_lossy()).unwrap();
+        assert_eq!(sessions::app_sessions(&restored, 86400).unwrap().len(), 1);
+
+        let mut backups = Backups::build(config, &target.to_string_lossy());
+        assert!(!backups.is_due(today.succ_opt().unwrap()));
+        let now = SystemTime::now();
+        let today = DateTime::<Local>::from(now).date_naive();
+        assert!(backups.is_due(today));
+        backups.start(now);
+        backups.running.take().unwrap().join().unwrap();
+        assert!(!backups.is_due(today));
+
+        fs::write(dir.join("junk.db"), "junk").unwrap();
+        assert!(verify(&dir.join("junk.db")).is_err());
+        assert!(restore(&dir.join("junk.db"), &target).is_err());
+        assert!(restore_bundle(&path, "hunter3", &target).is_err());
+        assert!(!dir.join("imported.db.restore.tmp").exists());
+        verify(&target).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+//! Encrypted and compressed database bundles.
+//!
+//! A bundle starts with a header of the magic `DECKTIME`, a version byte,
+//! the Argon2id memory, time and parallelism costs as little-endian u32, a
+//! 16 byte salt and a 12 byte nonce. It is followed by the deflated database
+//! encrypted with ChaCha20-Poly1305, with the header as associated data.
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    mem,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::Path,
+};
+
+const MAGIC: &[u8; 8] = b"DECKTIME";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 3 * 4 + SALT_LEN + NONCE_LEN;
+/// Bundles asking for more memory than this in KiB are rejected.
+const MAX_M_COST: u32 = 1024 * 1024;
+/// Bundles asking for more passes or lanes than this are rejected.
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 16;
+/// Bundles decompressing to more bytes than this are rejected.
+const MAX_DATA: u64 = 1024 * 1024 * 1024;
+
+/// Argon2id memory, time and parallelism costs.
+type Costs = (u32, u32, u32);
+
+const COSTS: Costs = (
+    Params::DEFAULT_M_COST,
+    Params::DEFAULT_T_COST,
+    Params::DEFAULT_P_COST,
+);
+
+fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    fs::File::open("/dev/urandom")?.read_exact(buf)
+}
+
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    (m_cost, t_cost, p_cost): Costs,
+) -> Result<Key, String> {
+    let params = Params::new(m_cost, t_cost, p_cost, Some(32)).map_err(|err| err.to_string())?;
+    let mut key = Key::default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| err.to_string())?;
+    Ok(key)
+}
+
+pub fn is_bundle(path: &Path) -> io::Result<bool> {
+    let mut magic = [0; MAGIC.len()];
+    match fs::File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn seal_with(data: &[u8], passphrase: &str, costs: Costs) -> Result<Vec<u8>, String> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).map_err(|err| err.to_string())?;
+    let compressed = encoder.finish().map_err(|err| err.to_string())?;
+
+    let mut salt = [0; SALT_LEN];
+    let mut nonce = [0; NONCE_LEN];
+    random_bytes(&mut salt).map_err(|err| err.to_string())?;
+    random_bytes(&mut nonce).map_err(|err| err.to_string())?;
+
+    let mut out = Vec::with_capacity(HEADER_LEN + compressed.len() + 16);
+    out.extend(MAGIC);
+    out.push(VERSION);
+    for cost in [costs.0, costs.1, costs.2] {
+        out.extend(cost.to_le_bytes());
+    }
+    out.extend(salt);
+    out.extend(nonce);
+
+    let key = derive_key(passphrase, &salt, costs)?;
+    let payload = Payload {
+        msg: &compressed,
+        aad: &out,
+    };
+    let ciphertext = ChaCha20Poly1305::new(&key)
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| "encryption error".to_owned())?;
+    out.extend(ciphertext);
+    Ok(out)
+}
+
+/// Compresses and encrypts a serialized database.
+pub fn seal(data: &[u8], passphrase: &str) -> Result<Vec<u8>, String> {
+    seal_with(data, passphrase, COSTS)
+}
+
+/// Decrypts and decompresses a bundle back into a serialized database.
+pub fn open(bundle: &[u8], passphrase: &str) -> Result<Vec<u8>, String> {
+    if bundle.len() < HEADER_LEN || !bundle.starts_with(MAGIC) {
+        return Err("not a decktime bundle".to_owned());
+    }
+    let (header, ciphertext) = bundle.split_at(HEADER_LEN);
+    let version = header[MAGIC.len()];
+    if version != VERSION {
+        return Err(format!(
+            "bundle version {version} is not supported, update decktime"
+        ));
+    }
+    let (costs, rest) = header[MAGIC.len() + 1..].split_at(3 * 4);
+    let cost = |i: usize| u32::from_le_bytes(costs[i * 4..i * 4 + 4].try_into().unwrap());
+    let costs = (cost(0), cost(1), cost(2));
+    if costs.0 > MAX_M_COST {
+        return Err(format!("bundle asks for {} KiB of memory", costs.0));
+    }
+    if costs.1 > MAX_T_COST || costs.2 > MAX_P_COST {
+        return Err(format!(
+            "bundle asks for {} passes over {} lanes",
+            costs.1, costs.2
+        ));
+    }
+    let (salt, nonce) = rest.split_at(SALT_LEN);
+
+    let key = derive_key(passphrase, salt, costs)?;
+    let payload = Payload {
+        msg: ciphertext,
+        aad: header,
+    };
+    let compressed = ChaCha20Poly1305::new(&key)
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| "wrong passphrase or damaged bundle".to_owned())?;
+
+    let mut data = Vec::new();
+    DeflateDecoder::new(compressed.as_slice())
+        .take(MAX_DATA + 1)
+        .read_to_end(&mut data)
+        .map_err(|err| err.to_string())?;
+    if data.len() as u64 > MAX_DATA {
+        return Err(format!("bundle is larger than {MAX_DATA} bytes"));
+    }
+    Ok(data)
+}
+
+/// Writes `bundle` to a temporary file next to `path` and moves it there, so
+/// an interrupted export does not leave a truncated bundle behind.
+pub fn write(path: &Path, bundle: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let _ = fs::remove_file(&tmp);
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut out| {
+            out.write_all(bundle)?;
+            out.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+}
+
+fn prompt(message: &str) -> io::Result<String> {
+    let mut tty = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")?;
+    write!(tty, "{message}")?;
+
+    let fd = tty.as_raw_fd();
+    let mut termios: libc::termios = unsafe { mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let saved = termios;
+    termios.c_lflag &= !libc::ECHO;
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) };
+    let mut line = String::new();
+    let result = BufReader::new(&tty).read_line(&mut line);
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+    writeln!(tty)?;
+
+    result?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// Reads the passphrase from `file`, or asks for it on the terminal, twice
+/// if `confirm` is set.
+pub fn read_passphrase(file: Option<&Path>, confirm: bool) -> Result<String, String> {
+    let passphrase = match file {
+        Some(file) => fs::read_to_string(file)
+            .map(|text| text.trim_end_matches(['\r', '\n']).to_owned())
+            .map_err(|err| format!("{file:?}: {err}"))?,
+        None => {
+            let passphrase = prompt("Passphrase: ").map_err(|err| err.to_string())?;
+            if confirm
+                && prompt("Repeat passphrase: ").map_err(|err| err.to_string())? != passphrase
+            {
+                return Err("passphrases do not match".to_owned());
+            }
+            passphrase
+        }
+    };
+    if passphrase.is_empty() {
+        return Err("empty passphrase".to_owned());
+    }
+    Ok(passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let costs = (64, 1, 1);
+        let data = b"SQLite format 3\0".repeat(100);
+        let bundle = seal_with(&data, "hunter2", costs).unwrap();
+        assert!(bundle.len() < data.len());
+        assert_eq!(open(&bundle, "hunter2").unwrap(), data);
+        // Salt and nonce are random.
+        assert_ne!(seal_with(&data, "hunter2", costs).unwrap(), bundle);
+
+        assert!(open(&bundle, "hunter3").is_err());
+        let mut tampered = bundle.clone();
+        tampered[MAGIC.len() + 1] ^= 1;
+        assert!(open(&tampered, "hunter2").is_err());
+        let mut newer = bundle.clone();
+        newer[MAGIC.len()] = VERSION + 1;
+        assert_eq!(
+            open(&newer, "hunter2"),
+            Err(format!(
+                "bundle version {} is not supported, update decktime",
+                VERSION + 1
+            ))
+        );
+        assert!(open(&data, "hunter2").is_err());
+
+        let costly = seal_with(&data, "hunter2", (64, MAX_T_COST + 1, 1)).unwrap();
+        assert_eq!(
+            open(&costly, "hunter2"),
+            Err(format!(
+                "bundle asks for {} passes over 1 lanes",
+                MAX_T_COST + 1
+            ))
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{
+    backup::BackupConfig, hooks::Hook, limits::Limit, metrics::MetricsConfig, mqtt::MqttConfig,
+};
+use serde::{Deserialize, Deserializer};
+use std::{fs, path::Path, time::Duration};
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(rename = "limit")]
+    pub limits: Vec<Limit>,
+    #[serde(rename = "hook")]
+    pub hooks: Vec<Hook>,
+    pub mqtt: Option<MqttConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub backup: Option<BackupConfig>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|err| format!("{path:?}: {err}"))?;
+        let config: Config = toml::from_str(&text).map_err(|err| format!("{path:?}: {err}"))?;
+        config
+            .validate()
+            .map_err(|err| format!("{path:?}: {err}"))?;
+        Ok(config)
+    }
+
+    /// Checks what the file format cannot express.
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, hook) in self.hooks.iter().enumerate() {
+            hook.validate()
+                .map_err(|err| format!("hook #{} ({:?}): {err}", i + 1, hook.command))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses durations like `90s`, `45m`, `2h` or `1h30m`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let mut total = 0;
+    let mut digits = String::new();
+    for c in s.trim().chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(format!("invalid duration {s:?}")),
+        };
+        let value: u64 = digits
+            .parse()
+            .map_err(|_| format!("invalid duration {s:?}"))?;
+        total += value * unit;
+        digits.clear();
+    }
+    if !digits.is_empty() || total == 0 && !s.trim().starts_with('0') {
+        return Err(format!("invalid duration {s:?}"));
+    }
+    Ok(Duration::from_secs(total))
+}
+
+pub fn deserialize_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("0m"), Ok(Duration::ZERO));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("15").is_err());
+        assert!(parse_duration("2x").is_err());
+    }
+}
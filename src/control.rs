@@ -0,0 +1,429 @@
+use crate::{db, schedule, sessions};
+use clap::Subcommand;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    cell::RefCell,
+    env, fs,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MAX_LINE: usize = 64 * 1024;
+/// Clients with more unread replies than this are dropped.
+const MAX_PENDING: usize = 1024 * 1024;
+
+/// Request of the line-delimited JSON protocol, e.g. `{"cmd":"status"}`.
+#[derive(Debug, Serialize, Deserialize, Subcommand)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum Request {
+    /// Show running apps and the daemon state
+    Status,
+    /// Commit playtime and running apps to the database
+    CommitNow,
+    /// Write accumulated playtime to the database
+    Flush,
+    /// Set the alias of an app, or clear it if no alias is given
+    SetAlias {
+        app_id: db::AppId,
+        alias: Option<String>,
+    },
+    /// Stop counting playtime until resumed
+    PauseTracking,
+    /// Count playtime again
+    ResumeTracking,
+    /// Reload limits and hooks from the config file
+    ReloadConfig,
+    /// Remove the playtime of a time window
+    Exclude(sessions::Exclusion),
+    /// Log a session played elsewhere
+    AddSession(sessions::NewSession),
+    /// Change a manually logged session
+    EditSession(sessions::SessionChange),
+    /// Delete a manually logged session
+    DeleteSession {
+        #[arg(long, help = "Session id")]
+        id: u64,
+    },
+    /// Show run counts and timings of the scheduled tasks
+    SchedulerStats,
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    Some(PathBuf::from(env::var_os("XDG_RUNTIME_DIR")?).join("decktime.sock"))
+}
+
+/// Sends a single request to the running daemon.
+pub fn send(path: &Path, request: &Request) -> io::Result<Value> {
+    let mut stream = UnixStream::connect(path)?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    Ok(serde_json::from_str(&response)?)
+}
+
+pub struct Context<'a> {
+    pub db: &'a RefCell<db::DeckDB>,
+    pub sched: &'a schedule::Scheduler,
+    pub reload: &'a mut dyn FnMut() -> Result<(), String>,
+}
+
+fn unix_ts(timestamp: SystemTime) -> u64 {
+    timestamp
+        .duration_since(UNIX_EPOCH)
+        .map(|ts| ts.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn execute(request: Request, ctx: &mut Context) -> Result<Value, String> {
+    let now = SystemTime::now();
+    let err = |err: rusqlite::Error| err.to_string();
+
+    match request {
+        Request::Status => {
+            let db = ctx.db.borrow();
+            let running = db
+                .running_apps()
+                .into_iter()
+                .map(|(app_id, started)| {
+                    Ok(json!({
+                        "app_id": app_id,
+                        "alias": db.get_alias(app_id)?,
+                        "started": unix_ts(started),
+                        "session_secs": now.duration_since(started).map(|d| d.as_secs()).unwrap_or(0),
+                    }))
+                })
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .map_err(err)?;
+            let commits = db.commit_stats();
+            Ok(json!({
+                "version": env!("CARGO_PKG_VERSION"),
+                "paused": db.is_paused(),
+                "user": db.user().map_err(err)?,
+                "running": running,
+                "commits": commits.count,
+                "last_commit_ms": commits.last.as_millis() as u64,
+                "missed_ticks": ctx.sched.missed_ticks(),
+            }))
+        }
+        Request::CommitNow => ctx
+            .db
+            .borrow_mut()
+            .commit(now)
+            .map(|_| json!(null))
+            .map_err(err),
+        Request::Flush => ctx
+            .db
+            .borrow_mut()
+            .dump_cache()
+            .map(|_| json!(null))
+            .map_err(err),
+        Request::SetAlias { app_id, alias } => ctx
+            .db
+            .borrow_mut()
+            .set_alias(app_id, alias.as_deref())
+            .map(|_| json!(null))
+            .map_err(err),
+        Request::PauseTracking => ctx
+            .db
+            .borrow_mut()
+            .set_paused(now, true)
+            .map(|_| json!(null))
+            .map_err(err),
+        Request::ResumeTracking => ctx
+            .db
+            .borrow_mut()
+            .set_paused(now, false)
+            .map(|_| json!(null))
+            .map_err(err),
+        Request::ReloadConfig => (ctx.reload)().map(|_| json!(null)),
+        Request::SchedulerStats => Ok(json!(ctx.sched.stats())),
+        Request::Exclude(exclusion) => {
+            exclusion.validate()?;
+            ctx.db
+                .borrow_mut()
+                .exclude(exclusion.from, exclusion.to, exclusion.app)
+                .map(|removed| json!({ "removed_secs": removed }))
+                .map_err(err)
+        }
+        Request::AddSession(session) => ctx
+            .db
+            .borrow_mut()
+            .edit_sessions(|tx, user_id| session.add(tx, user_id, unix_ts(now)))
+            .map(|id| json!({ "id": id })),
+        Request::EditSession(change) => ctx
+            .db
+            .borrow_mut()
+            .edit_sessions(|tx, _| change.apply(tx, unix_ts(now)))
+            .map(|_| json!(null)),
+        Request::DeleteSession { id } => ctx
+            .db
+            .borrow_mut()
+            .edit_sessions(|tx, _| sessions::delete_session(tx, id))
+            .map(|_| json!(null)),
+    }
+}
+
+fn response(result: Result<Value, String>) -> Value {
+    match result {
+        Ok(result) => json!({ "ok": true, "result": result }),
+        Err(error) => json!({ "ok": false, "error": error }),
+    }
+}
+
+struct Client {
+    stream: UnixStream,
+    buf: Vec<u8>,
+    /// Replies not written yet, sent once the socket is writable.
+    out: Vec<u8>,
+    /// The client shut down its side, it is dropped once the replies are
+    /// written.
+    eof: bool,
+}
+
+impl Client {
+    /// Reads what is available and writes pending replies, returns `false`
+    /// once the client is gone.
+    fn read(&mut self, handler: &mut impl FnMut(Request) -> Result<Value, String>) -> bool {
+        let mut chunk = [0u8; 4096];
+        while !self.eof {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    // The last request may lack the newline.
+                    if !self.buf.is_empty() && self.buf.last() != Some(&b'\n') {
+                        self.buf.push(b'\n');
+                    }
+                    self.eof = true;
+                }
+                Ok(len) => self.buf.extend(&chunk[..len]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    debug!("control client error: {err}");
+                    return false;
+                }
+            }
+        }
+
+        while let Some(pos) = self.buf.iter().position(|&c| c == b'\n') {
+            let line: Vec<u8> = self.buf.drain(..=pos).collect();
+            let result = serde_json::from_slice::<Request>(&line)
+                .map_err(|err| format!("invalid request: {err}"))
+                .and_then(&mut *handler);
+            self.out.extend(response(result).to_string().as_bytes());
+            self.out.push(b'\n');
+        }
+
+        while !self.out.is_empty() {
+            match self.stream.write(&self.out) {
+                Ok(0) => return false,
+                Ok(len) => {
+                    self.out.drain(..len);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    debug!("control client error: {err}");
+                    return false;
+                }
+            }
+        }
+        if self.out.len() > MAX_PENDING {
+            debug!("control client does not read its replies");
+            return false;
+        }
+        !(self.eof && self.out.is_empty()) && self.buf.len() <= MAX_LINE
+    }
+}
+
+/// Control socket served from the main loop, so requests are handled
+/// between scheduler ticks by the single owner of the database.
+pub struct Control {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: Vec<Client>,
+}
+
+impl Control {
+    pub fn bind(path: &Path) -> io::Result<Control> {
+        if path.exists() {
+            match UnixStream::connect(path) {
+                Ok(_) => return Err(io::Error::new(ErrorKind::AddrInUse, "daemon is running")),
+                Err(_) => fs::remove_file(path)?,
+            }
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        info!("listening on {path:?}");
+        Ok(Control {
+            path: path.to_owned(),
+            listener,
+            clients: Vec::new(),
+        })
+    }
+
+    pub fn fds(&self) -> Vec<RawFd> {
+        let mut fds = vec![self.listener.as_raw_fd()];
+        fds.extend(
+            self.clients
+                .iter()
+                .filter(|client| !client.eof)
+                .map(|client| client.stream.as_raw_fd()),
+        );
+        fds
+    }
+
+    /// File descriptors of clients with pending replies.
+    pub fn write_fds(&self) -> Vec<RawFd> {
+        self.clients
+            .iter()
+            .filter(|client| !client.out.is_empty())
+            .map(|client| client.stream.as_raw_fd())
+            .collect()
+    }
+
+    /// Accepts new clients and answers all complete requests.
+    pub fn process(&mut self, mut handler: impl FnMut(Request) -> Result<Value, String>) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => match stream.set_nonblocking(true) {
+                    Ok(()) => self.clients.push(Client {
+                        stream,
+                        buf: Vec::new(),
+                        out: Vec::new(),
+                        eof: false,
+                    }),
+                    Err(err) => warn!("control accept error: {err}"),
+                },
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    warn!("control accept error: {err}");
+                    break;
+                }
+            }
+        }
+        self.clients.retain_mut(|client| client.read(&mut handler));
+    }
+}
+
+impl Drop for Control {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn protocol() {
+        let path = env::temp_dir().join(format!("decktime-test-{}.sock", std::process::id()));
+        let mut control = Control::bind(&path).unwrap();
+        assert!(Control::bind(&path).is_err());
+
+        let client_path = path.clone();
+        let handle = thread::spawn(move || {
+            let alias = Request::SetAlias {
+                app_id: 570,
+                alias: Some("Dota 2".to_owned()),
+            };
+            (
+                send(&client_path, &alias).unwrap(),
+                send(&client_path, &Request::Flush).unwrap(),
+            )
+        });
+
+        let mut requests = Vec::new();
+        while requests.len() < 2 {
+            control.process(|request| {
+                let reply = match request {
+                    Request::Flush => Err("nothing to flush".to_owned()),
+                    _ => Ok(json!(1)),
+                };
+                requests.push(request);
+                reply
+            });
+            thread::sleep(Duration::from_millis(10));
+        }
+        let (alias, flush) = handle.join().unwrap();
+
+        assert!(matches!(
+            &requests[0],
+            Request::SetAlias { app_id: 570, alias: Some(alias) } if alias == "Dota 2"
+        ));
+        assert_eq!(alias, json!({ "ok": true, "result": 1 }));
+        assert_eq!(flush, json!({ "ok": false, "error": "nothing to flush" }));
+
+        drop(control);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn slow_reader() {
+        const COUNT: usize = 20_000;
+        let path = env::temp_dir().join(format!("decktime-slow-{}.sock", std::process::id()));
+        let mut control = Control::bind(&path).unwrap();
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let writer = thread::spawn(move || {
+            let requests = b"{\"cmd\":\"status\"}\n".repeat(COUNT);
+            writer.write_all(&requests).unwrap();
+        });
+
+        let mut handled = 0;
+        while handled < COUNT {
+            control.process(|_| {
+                handled += 1;
+                Ok(json!(1))
+            });
+            thread::sleep(Duration::from_millis(1));
+        }
+        // The replies do not fit into the socket buffer and wait in the
+        // client's output buffer instead of blocking the daemon.
+        assert_eq!(control.write_fds().len(), 1);
+        writer.join().unwrap();
+
+        let reader = thread::spawn(move || BufReader::new(stream).lines().take(COUNT).count());
+        while !reader.is_finished() {
+            control.process(|_| Err("unexpected request".to_owned()));
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(reader.join().unwrap(), COUNT);
+        assert!(control.write_fds().is_empty());
+    }
+
+    #[test]
+    fn half_close() {
+        let path = env::temp_dir().join(format!("decktime-eof-{}.sock", std::process::id()));
+        let mut control = Control::bind(&path).unwrap();
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream
+            .write_all(b"{\"cmd\":\"flush\"}\n{\"cmd\":\"status\"}")
+            .unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let reader = thread::spawn(move || BufReader::new(stream).lines().count());
+
+        let mut handled = Vec::new();
+        while !reader.is_finished() {
+            control.process(|request| {
+                handled.push(format!("{request:?}"));
+                Ok(json!(null))
+            });
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(reader.join().unwrap(), 2);
+        assert_eq!(handled, vec!["Flush", "Status"]);
+        assert!(control.clients.is_empty());
+    }
+}
@@ -1,37 +1,96 @@
+use crate::{sessions, steam::SteamUser};
+use chrono::{Local, TimeZone};
 use log::{debug, error, info, trace, warn};
-use rusqlite::{Connection, Error, Result};
+use rusqlite::{Connection, Error, Result, Transaction};
 use std::{
-    collections::{HashMap, HashSet},
-    time::{SystemTime, UNIX_EPOCH},
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 pub type AppId = u32;
-const THIS_APP_ID: AppId = 0;
+pub const THIS_APP_ID: AppId = 0;
+pub type UserId = u32;
+pub const UNKNOWN_USER_ID: UserId = 0;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum EventType {
     Running = 0,
     Started,
     Stopped,
     Suspended,
     Resumed,
+    LimitReached,
+    TrackingPaused,
+    TrackingResumed,
 }
 
-struct AppCache {
-    apps: HashMap<AppId, u64>,
-    timestamp_h: u64,
+impl EventType {
+    pub fn name(self) -> &'static str {
+        match self {
+            EventType::Running => "running",
+            EventType::Started => "started",
+            EventType::Stopped => "stopped",
+            EventType::Suspended => "suspended",
+            EventType::Resumed => "resumed",
+            EventType::LimitReached => "limit_reached",
+            EventType::TrackingPaused => "tracking_paused",
+            EventType::TrackingResumed => "tracking_resumed",
+        }
+    }
+}
+
+/// Origin of events and playtime rows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Observed = 0,
+    Manual,
+}
+
+/// Event as seen by listeners, `app_id` is `None` for events of decktime itself.
+pub struct Event {
+    pub timestamp: SystemTime,
+    pub app_id: Option<AppId>,
+    pub alias: Option<String>,
+    pub event_type: EventType,
+    /// Time since the app was started, for app events.
+    pub session: Option<Duration>,
+}
+
+pub type Listener = Box<dyn FnMut(&Event)>;
+
+#[derive(Default, Clone, Copy)]
+pub struct CommitStats {
+    pub count: u64,
+    pub total: Duration,
+    pub last: Duration,
 }
 
+/// Playtime not written to the database yet, per bucket (unix time divided
+/// by the bucket size) and app.
+type AppCache = BTreeMap<u64, HashMap<AppId, u64>>;
+
+pub const DEFAULT_BUCKET_SECS: u64 = 60 * 60;
+
+/// Schema version written by `migrate`.
+pub const SCHEMA_VERSION: u32 = 6;
+
 pub struct DeckDB {
     conn: Connection,
+    bucket_secs: u64,
     last_timestamp: u64,
     cache: AppCache,
-    running_apps: HashSet<AppId>,
+    running_apps: HashMap<AppId, SystemTime>,
+    user_id: UserId,
+    listeners: Vec<Listener>,
+    commit_stats: CommitStats,
+    paused: bool,
 }
 
 impl DeckDB {
-    pub fn build(path: &str, timestamp: SystemTime) -> Result<DeckDB> {
+    /// Opens the database, creating and migrating the schema if necessary.
+    pub fn open(path: &str) -> Result<Connection> {
         let mut conn = Connection::open(path)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
 
         let tx = conn.transaction()?;
 
@@ -85,6 +144,50 @@ impl DeckDB {
             (),
         )?;
 
+        Self::migrate(&tx)?;
+
+        tx.commit()?;
+        Ok(conn)
+    }
+
+    /// Bucket size of `timeline` in seconds.
+    pub fn bucket_secs(conn: &Connection) -> Result<u64> {
+        conn.query_row(
+            "select value from meta where key = 'bucket_secs'",
+            (),
+            |row| row.get(0),
+        )
+    }
+
+    /// Opens the database for tracking, `bucket_secs` only applies to
+    /// databases without any playtime yet.
+    pub fn build(path: &str, timestamp: SystemTime, bucket_secs: Option<u64>) -> Result<DeckDB> {
+        let mut conn = Self::open(path)?;
+
+        let stored = Self::bucket_secs(&conn)?;
+        let bucket_secs = match bucket_secs {
+            Some(bucket_secs) if bucket_secs != stored => {
+                let empty: bool =
+                    conn.query_row("select not exists (select * from timeline)", (), |row| {
+                        row.get(0)
+                    })?;
+                if empty {
+                    conn.execute(
+                        "update meta set value = ?1 where key = 'bucket_secs'",
+                        (bucket_secs,),
+                    )?;
+                    info!("using {bucket_secs}s buckets");
+                    bucket_secs
+                } else {
+                    warn!("database already uses {stored}s buckets, ignoring {bucket_secs}s");
+                    stored
+                }
+            }
+            _ => stored,
+        };
+
+        let tx = conn.transaction()?;
+
         assert_eq!(EventType::Running as u32, 0);
         tx.execute(
             "update events set event_type = ?1 where event_type = ?2",
@@ -95,27 +198,158 @@ impl DeckDB {
             row.get(0).or(Ok(0))
         })?;
 
+        let this_object_id = Self::get_object_id(&tx, THIS_APP_ID)?;
+        let paused = tx
+            .query_row(
+                "select event_type = ?1 from events \
+                    where object_id = ?2 and event_type in (?1, ?3) \
+                    order by timestamp desc, rowid desc limit 1",
+                (
+                    EventType::TrackingPaused as u32,
+                    this_object_id,
+                    EventType::TrackingResumed as u32,
+                ),
+                |row| row.get(0),
+            )
+            .or_else(|err| match err {
+                Error::QueryReturnedNoRows => Ok(false),
+                err => Err(err),
+            })?;
+
         tx.commit()?;
 
         let mut db = DeckDB {
             conn,
+            bucket_secs,
             last_timestamp,
-            cache: AppCache {
-                apps: HashMap::new(),
-                timestamp_h: 0,
-            },
-            running_apps: HashSet::new(),
+            cache: AppCache::new(),
+            running_apps: HashMap::new(),
+            user_id: UNKNOWN_USER_ID,
+            listeners: Vec::new(),
+            commit_stats: CommitStats::default(),
+            paused,
         };
         db.validate_timestamp(timestamp)?;
-        db.load_cache(to_unix_ts(timestamp) / 60 / 60)?;
         db.event(timestamp, None, EventType::Started)?;
+        if paused {
+            warn!("tracking is paused");
+        }
 
         info!("database {path:?} opened successfully");
 
         Ok(db)
     }
 
-    fn get_object_id(conn: &Connection, app_id: AppId) -> Result<u32> {
+    fn migrate(tx: &Transaction) -> Result<()> {
+        let version: u32 = tx.query_row("pragma user_version", (), |row| row.get(0))?;
+
+        if version < 1 {
+            info!("migrating database to version 1");
+            tx.execute_batch(
+                "create table users ( \
+                    user_id integer not null, \
+                    steam_id integer unique not null, \
+                    account_name text not null, \
+                    persona_name text, \
+                    primary key (user_id) \
+                ); \
+                alter table events add column user_id integer not null default 0; \
+                alter table backup_events add column user_id integer not null default 0; \
+                create table timeline_new ( \
+                    timestamp integer not null, \
+                    object_id integer not null, \
+                    user_id integer not null default 0, \
+                    value integer not null, \
+                    primary key (timestamp, object_id, user_id), \
+                    foreign key (object_id) references objects (object_id) \
+                ); \
+                insert into timeline_new (timestamp, object_id, value) \
+                    select timestamp, object_id, value from timeline; \
+                drop table timeline; \
+                alter table timeline_new rename to timeline; \
+                pragma user_version = 1;",
+            )?;
+        }
+
+        if version < 2 {
+            info!("migrating database to version 2");
+            tx.execute_batch(
+                "create table exclusions ( \
+                    exclusion_id integer not null, \
+                    start_ts integer not null, \
+                    end_ts integer not null, \
+                    object_id integer, \
+                    seconds integer not null, \
+                    primary key (exclusion_id), \
+                    foreign key (object_id) references objects (object_id) \
+                ); \
+                pragma user_version = 2;",
+            )?;
+        }
+
+        if version < 3 {
+            info!("migrating database to version 3");
+            tx.execute_batch(
+                "create table sessions ( \
+                    session_id integer not null, \
+                    object_id integer not null, \
+                    start_ts integer not null, \
+                    end_ts integer not null, \
+                    primary key (session_id), \
+                    foreign key (object_id) references objects (object_id) \
+                ); \
+                alter table events add column source integer not null default 0; \
+                alter table backup_events add column source integer not null default 0; \
+                create table timeline_new ( \
+                    timestamp integer not null, \
+                    object_id integer not null, \
+                    user_id integer not null default 0, \
+                    source integer not null default 0, \
+                    value integer not null, \
+                    primary key (timestamp, object_id, user_id, source), \
+                    foreign key (object_id) references objects (object_id) \
+                ); \
+                insert into timeline_new (timestamp, object_id, user_id, value) \
+                    select timestamp, object_id, user_id, value from timeline; \
+                drop table timeline; \
+                alter table timeline_new rename to timeline; \
+                pragma user_version = 3;",
+            )?;
+        }
+
+        if version < 4 {
+            info!("migrating database to version 4");
+            tx.execute_batch(
+                "alter table timeline add column utc_offset integer; \
+                pragma user_version = 4;",
+            )?;
+        }
+
+        if version < 5 {
+            info!("migrating database to version 5");
+            tx.execute_batch(&format!(
+                "create table meta ( \
+                    key text not null, \
+                    value integer not null, \
+                    primary key (key) \
+                ); \
+                insert into meta (key, value) values ('bucket_secs', {DEFAULT_BUCKET_SECS}); \
+                pragma user_version = 5;"
+            ))?;
+        }
+
+        if version < 6 {
+            info!("migrating database to version 6");
+            tx.execute_batch(
+                "alter table sessions add column user_id integer not null default 0; \
+                pragma user_version = 6;",
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_object_id(conn: &Connection, app_id: AppId) -> Result<u32> {
         conn.query_row(
             "select object_id from objects where app_id = ?1",
             (app_id,),
@@ -143,8 +377,8 @@ impl DeckDB {
             )?;
             tx.execute(
                 "insert into backup_events \
-                (backup_id, timestamp, object_id, event_type) \
-                select ?1, timestamp, object_id, event_type from events \
+                (backup_id, timestamp, object_id, event_type, user_id, source) \
+                select ?1, timestamp, object_id, event_type, user_id, source from events \
                 where timestamp > ?2 \
                 order by rowid asc",
                 (backup_id, timestamp_s),
@@ -161,69 +395,100 @@ impl DeckDB {
         Ok(())
     }
 
-    fn load_cache(&mut self, timestamp_h: u64) -> Result<()> {
-        debug!("loading cache with timestamp={timestamp_h}");
-
-        let mut stmt = self.conn.prepare_cached(
-            "select app_id, value from timeline \
-                join objects on timeline.object_id = objects.object_id \
-                where timestamp = ?1",
-        )?;
-
-        let apps = stmt
-            .query_map((timestamp_h,), |row| Ok((row.get(0)?, row.get(1)?)))?
-            .filter_map(Result::ok)
-            .collect();
-
-        self.cache = AppCache { apps, timestamp_h };
-
-        Ok(())
-    }
-
-    fn dump_cache(&mut self) -> Result<()> {
-        debug!("dumping cache with timestamp={}", self.cache.timestamp_h);
+    /// Adds the cached playtime to the database along with the local UTC
+    /// offset at the time of the commit and empties the cache.
+    pub fn dump_cache(&mut self) -> Result<()> {
+        debug!("dumping cache with {} buckets", self.cache.len());
 
         let tx = self.conn.transaction()?;
         {
-            let mut stmt =
-                tx.prepare_cached("insert or replace into timeline values (?1, ?2, ?3)")?;
-            for (&app_id, &value) in self.cache.apps.iter() {
-                let object_id = Self::get_object_id(&(*tx), app_id)?;
-                stmt.execute((self.cache.timestamp_h, object_id, value))?;
+            let mut stmt = tx.prepare_cached(
+                "insert into timeline (timestamp, object_id, user_id, source, value, utc_offset) \
+                select ?1, ?2, ?3, ?4, max(min(?5, ?7 - coalesce(sum(value), 0)), 0), ?6 \
+                from timeline where timestamp = ?1 and object_id = ?2 \
+                on conflict (timestamp, object_id, user_id, source) do update set \
+                value = value + excluded.value, utc_offset = excluded.utc_offset",
+            )?;
+            for (&bucket, apps) in self.cache.iter() {
+                let bucket_start = bucket * self.bucket_secs;
+                let utc_offset = local_offset(
+                    self.last_timestamp
+                        .clamp(bucket_start, bucket_start + self.bucket_secs - 1),
+                );
+                for (&app_id, &value) in apps.iter() {
+                    let object_id = Self::get_object_id(&tx, app_id)?;
+                    stmt.execute((
+                        bucket,
+                        object_id,
+                        self.user_id,
+                        Source::Observed as u32,
+                        value,
+                        utc_offset,
+                        self.bucket_secs,
+                    ))?;
+                }
             }
         }
+        tx.commit()?;
 
-        tx.commit()
+        self.cache.clear();
+        Ok(())
     }
 
-    pub fn update(&mut self, app_id: AppId, value: u64) {
+    /// Adds the `value` seconds before `timestamp` to the playtime of
+    /// `app_id`, split between the buckets they fall into. Playtime of a
+    /// bucket never exceeds the bucket length.
+    pub fn update(&mut self, app_id: AppId, timestamp: SystemTime, value: u64) {
         trace!("update with app_id={app_id} value={value}");
 
-        match self.cache.apps.get_mut(&app_id) {
-            Some(entry) => *entry += value,
-            None => {
-                self.cache.apps.insert(app_id, value);
+        if self.paused {
+            return;
+        }
+
+        let end = to_unix_ts(timestamp);
+        let mut start = end.saturating_sub(value);
+        while start < end {
+            let bucket = start / self.bucket_secs;
+            let next = end.min((bucket + 1) * self.bucket_secs);
+            let entry = self
+                .cache
+                .entry(bucket)
+                .or_default()
+                .entry(app_id)
+                .or_insert(0);
+            if *entry + next - start > self.bucket_secs {
+                warn!(
+                    "playtime of app_id={app_id} exceeds {}s in bucket {bucket}, clamping",
+                    self.bucket_secs
+                );
             }
+            *entry = (*entry + next - start).min(self.bucket_secs);
+            start = next;
         }
     }
 
     pub fn commit(&mut self, timestamp: SystemTime) -> Result<()> {
+        let started = Instant::now();
         self.validate_timestamp(timestamp)?;
 
-        let timestamp_h = to_unix_ts(timestamp) / 60 / 60;
-        debug!("commit with timestamp={timestamp_h}");
+        debug!("commit with timestamp={}", to_unix_ts(timestamp));
 
         self.event(timestamp, None, EventType::Running)?;
 
         self.dump_cache()?;
-        if timestamp_h != self.cache.timestamp_h {
-            debug!("reload cache with timestamp={timestamp_h}");
-            self.load_cache(timestamp_h)?;
-        }
+
+        let elapsed = started.elapsed();
+        self.commit_stats.count += 1;
+        self.commit_stats.total += elapsed;
+        self.commit_stats.last = elapsed;
 
         Ok(())
     }
 
+    pub fn commit_stats(&self) -> CommitStats {
+        self.commit_stats
+    }
+
     pub fn event(
         &mut self,
         timestamp: SystemTime,
@@ -242,8 +507,8 @@ impl DeckDB {
             None => THIS_APP_ID,
         };
 
-        const SQL_INSERT: &'static str =
-            "insert into events (timestamp, object_id, event_type) values (?1, ?2, ?3)";
+        const SQL_INSERT: &str = "insert into events \
+            (timestamp, object_id, event_type, user_id) values (?1, ?2, ?3, ?4)";
         match event_type {
             EventType::Started | EventType::Stopped => {
                 let object_id = Self::get_object_id(&self.conn, app_id)?;
@@ -258,10 +523,21 @@ impl DeckDB {
                         EventType::Running
                     );
                 }
-                tx.execute(SQL_INSERT, (timestamp_s, object_id, event_type as u32))?;
+                tx.execute(
+                    SQL_INSERT,
+                    (timestamp_s, object_id, event_type as u32, self.user_id),
+                )?;
                 tx.commit()?;
             }
 
+            EventType::LimitReached | EventType::TrackingPaused | EventType::TrackingResumed => {
+                let object_id = Self::get_object_id(&self.conn, app_id)?;
+                self.conn.execute(
+                    SQL_INSERT,
+                    (timestamp_s, object_id, event_type as u32, self.user_id),
+                )?;
+            }
+
             EventType::Suspended | EventType::Resumed | EventType::Running => {
                 let tx = self.conn.transaction()?;
                 if let EventType::Running = event_type {
@@ -279,11 +555,12 @@ impl DeckDB {
                 }
                 {
                     let mut stmt = tx.prepare_cached(SQL_INSERT)?;
-                    for &app in self.running_apps.iter() {
+                    for &app in self.running_apps.keys() {
                         stmt.execute((
                             timestamp_s,
-                            Self::get_object_id(&(*tx), app)?,
+                            Self::get_object_id(&tx, app)?,
                             event_type as u32,
+                            self.user_id,
                         ))?;
                     }
                 }
@@ -291,14 +568,239 @@ impl DeckDB {
             }
         }
 
-        let ok = match event_type {
-            EventType::Started => self.running_apps.insert(app_id),
-            EventType::Stopped => self.running_apps.remove(&app_id),
-            _ => true,
+        let (ok, started) = match event_type {
+            EventType::Started => match self.running_apps.entry(app_id) {
+                Entry::Occupied(_) => (false, None),
+                Entry::Vacant(entry) => (true, Some(*entry.insert(timestamp))),
+            },
+            EventType::Stopped => {
+                let started = self.running_apps.remove(&app_id);
+                (started.is_some(), started)
+            }
+            _ => (true, self.running_apps.get(&app_id).copied()),
         };
 
         if !ok {
             warn!("duplicated event with app_id={app_id} and event_type={event_type:?}");
+            return Ok(());
+        }
+
+        if !self.listeners.is_empty() {
+            let app_id = Some(app_id).filter(|&app_id| app_id != THIS_APP_ID);
+            let event = Event {
+                timestamp,
+                app_id,
+                alias: match app_id {
+                    Some(app_id) => self.get_alias(app_id)?,
+                    None => None,
+                },
+                event_type,
+                session: started.and_then(|started| timestamp.duration_since(started).ok()),
+            };
+            self.listeners
+                .iter_mut()
+                .for_each(|listener| listener(&event));
+        }
+
+        Ok(())
+    }
+
+    pub fn subscribe(&mut self, listener: Listener) {
+        self.listeners.push(listener);
+    }
+
+    pub fn get_alias(&self, app_id: AppId) -> Result<Option<String>> {
+        self.conn
+            .query_row(
+                "select alias from objects where app_id = ?1",
+                (app_id,),
+                |row| row.get(0),
+            )
+            .or_else(|err| match err {
+                Error::QueryReturnedNoRows => Ok(None),
+                err => Err(err),
+            })
+    }
+
+    /// Seconds played since `since`, by a single app or by all apps together.
+    ///
+    /// Playtime of the bucket containing `since` is prorated, assuming it was
+    /// spread evenly across the bucket.
+    pub fn playtime(&self, app_id: Option<AppId>, since: SystemTime) -> Result<u64> {
+        let since_s = to_unix_ts(since);
+        let since_b = since_s / self.bucket_secs;
+        let remaining = (since_b + 1) * self.bucket_secs - since_s;
+        let prorate = |value: u64| value * remaining / self.bucket_secs;
+
+        let stored: u64 = self.conn.query_row(
+            "select coalesce(sum(case when timestamp = ?1 then ?2 * value / ?3 else value end), 0) \
+                from timeline \
+                join objects on timeline.object_id = objects.object_id \
+                where timestamp >= ?1 and (?4 is null or app_id = ?4)",
+            (since_b, remaining, self.bucket_secs, app_id),
+            |row| row.get(0),
+        )?;
+
+        let cached: u64 = self
+            .cache
+            .range(since_b..)
+            .map(|(&bucket, apps)| {
+                let value = apps
+                    .iter()
+                    .filter(|(&id, _)| app_id.is_none_or(|app_id| app_id == id))
+                    .map(|(_, &value)| value)
+                    .sum();
+                match bucket == since_b {
+                    true => prorate(value),
+                    false => value,
+                }
+            })
+            .sum();
+
+        Ok(stored + cached)
+    }
+
+    /// Total playtime and alias of every app, including uncommitted time.
+    pub fn app_playtimes(&self) -> Result<Vec<(AppId, Option<String>, u64)>> {
+        let mut stmt = self.conn.prepare_cached(
+            "select app_id, alias, coalesce(sum(value), 0) \
+                from objects left join timeline on timeline.object_id = objects.object_id \
+                where app_id != ?1 \
+                group by objects.object_id",
+        )?;
+        let mut playtimes: Vec<(AppId, Option<String>, u64)> = stmt
+            .query_map((THIS_APP_ID,), |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })?
+            .collect::<Result<_>>()?;
+        for (app_id, _, value) in playtimes.iter_mut() {
+            *value += self
+                .cache
+                .values()
+                .filter_map(|apps| apps.get(app_id))
+                .sum::<u64>();
+        }
+        Ok(playtimes)
+    }
+
+    /// Running apps with their start time, not including decktime itself.
+    pub fn running_apps(&self) -> Vec<(AppId, SystemTime)> {
+        self.running_apps
+            .iter()
+            .map(|(&app_id, &started)| (app_id, started))
+            .filter(|&(app_id, _)| app_id != THIS_APP_ID)
+            .collect()
+    }
+
+    pub fn set_alias(&mut self, app_id: AppId, alias: Option<&str>) -> Result<()> {
+        let object_id = Self::get_object_id(&self.conn, app_id)?;
+        self.conn.execute(
+            "update objects set alias = ?1 where object_id = ?2",
+            (alias, object_id),
+        )?;
+        info!("alias of app_id={app_id} set to {alias:?}");
+        Ok(())
+    }
+
+    /// Account name of the active Steam user.
+    pub fn user(&self) -> Result<Option<String>> {
+        self.conn
+            .query_row(
+                "select account_name from users where user_id = ?1",
+                (self.user_id,),
+                |row| row.get(0),
+            )
+            .or_else(|err| match err {
+                Error::QueryReturnedNoRows => Ok(None),
+                err => Err(err),
+            })
+    }
+
+    /// Stops or restarts accumulating playtime, events are still recorded.
+    pub fn set_paused(&mut self, timestamp: SystemTime, paused: bool) -> Result<()> {
+        if paused == self.paused {
+            return Ok(());
+        }
+        info!("tracking {}", if paused { "paused" } else { "resumed" });
+        self.paused = paused;
+        let event_type = match paused {
+            true => EventType::TrackingPaused,
+            false => EventType::TrackingResumed,
+        };
+        self.event(timestamp, None, event_type)
+    }
+
+    /// Time of the latest event or commit, earlier ones are taken for time
+    /// travel.
+    pub fn last_timestamp(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.last_timestamp)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Removes the playtime between `from` and `to` of one or all apps,
+    /// returns the number of seconds removed.
+    pub fn exclude(&mut self, from: u64, to: u64, app_id: Option<AppId>) -> Result<u64> {
+        self.dump_cache()?;
+
+        let tx = self.conn.transaction()?;
+        let removed = sessions::exclude(&tx, from, to, app_id)?;
+        tx.commit()?;
+
+        Ok(removed)
+    }
+
+    /// Runs `edit` on the manually logged sessions in a transaction that is
+    /// only committed if it succeeds, `edit` gets the active user.
+    pub fn edit_sessions<T>(
+        &mut self,
+        edit: impl FnOnce(&Transaction, UserId) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let tx = self.conn.transaction().map_err(|err| err.to_string())?;
+        let result = edit(&tx, self.user_id)?;
+        tx.commit().map_err(|err| err.to_string())?;
+        Ok(result)
+    }
+
+    /// Number of recorded events of decktime itself with the given type.
+    pub fn count_events(&self, event_type: EventType) -> Result<u64> {
+        self.conn.query_row(
+            "select count(*) from events \
+                join objects on events.object_id = objects.object_id \
+                where app_id = ?1 and event_type = ?2",
+            (THIS_APP_ID, event_type as u32),
+            |row| row.get(0),
+        )
+    }
+
+    /// Stores or updates the Steam account, returns its id.
+    pub fn get_user_id(conn: &Connection, user: &SteamUser) -> Result<UserId> {
+        conn.query_row(
+            "insert into users (steam_id, account_name, persona_name) values (?1, ?2, ?3) \
+            on conflict (steam_id) do update set \
+            account_name = excluded.account_name, persona_name = excluded.persona_name \
+            returning user_id",
+            (user.steam_id, &user.account_name, &user.persona_name),
+            |row| row.get(0),
+        )
+    }
+
+    /// Switches the account that new events and playtime are attributed to.
+    pub fn set_user(&mut self, user: Option<&SteamUser>) -> Result<()> {
+        let user_id = match user {
+            Some(user) => Self::get_user_id(&self.conn, user)?,
+            None => UNKNOWN_USER_ID,
+        };
+
+        if user_id != self.user_id {
+            info!(
+                "active user changed to {:?}",
+                user.map(|user| &user.account_name)
+            );
+            self.dump_cache()?;
+            self.user_id = user_id;
         }
 
         Ok(())
@@ -306,7 +808,7 @@ impl DeckDB {
 
     pub fn flush(&mut self, timestamp: SystemTime) -> Result<()> {
         self.dump_cache()?;
-        for app_id in self.running_apps.clone() {
+        for app_id in self.running_apps.keys().copied().collect::<Vec<_>>() {
             self.event(timestamp, Some(app_id), EventType::Stopped)?;
         }
         Ok(())
@@ -325,9 +827,17 @@ fn to_unix_ts(timestamp: SystemTime) -> u64 {
     timestamp.duration_since(UNIX_EPOCH).unwrap().as_secs()
 }
 
+/// Local UTC offset in seconds at `timestamp`.
+pub fn local_offset(timestamp: u64) -> i32 {
+    Local
+        .timestamp_opt(timestamp as i64, 0)
+        .single()
+        .map_or(0, |local| local.offset().local_minus_utc())
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{fs, time::Duration};
+    use std::fs;
 
     use super::*;
 
@@ -337,25 +847,25 @@ mod tests {
 
     #[test]
     fn timetraveler() {
-        env_logger::builder().init();
+        let _ = env_logger::builder().is_test(true).try_init();
 
         let path = "./test.db";
         let app_id = Some(1);
 
         let _ = fs::remove_file(path);
-        let mut db = DeckDB::build(path, time(1000)).unwrap();
+        let mut db = DeckDB::build(path, time(1000), None).unwrap();
 
         db.event(time(1010), app_id, EventType::Started).unwrap();
         db.event(time(1050), app_id, EventType::Running).unwrap();
         drop(db);
 
-        let mut db = DeckDB::build(path, time(1010)).unwrap();
+        let mut db = DeckDB::build(path, time(1010), None).unwrap();
         db.event(time(1020), None, EventType::Suspended).unwrap();
         db.event(time(1040), None, EventType::Resumed).unwrap();
         db.flush(time(1050)).unwrap();
         drop(db);
 
-        let db = DeckDB::build(path, time(2000)).unwrap();
+        let db = DeckDB::build(path, time(2000), None).unwrap();
         let mut stmt = db.conn.prepare("select * from events").unwrap();
         let mut data = stmt
             .query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
@@ -386,4 +896,200 @@ mod tests {
         data.sort();
         assert_eq!(data, vec![(1, 1050, 1, 2), (1, 1050, 2, 2)]);
     }
+
+    #[test]
+    fn users() {
+        let _ = env_logger::builder().is_test(true).try_init();
+
+        let user = |steam_id, account_name: &str| SteamUser {
+            steam_id,
+            account_name: account_name.to_owned(),
+            persona_name: None,
+        };
+
+        let mut db = DeckDB::build(":memory:", time(3600), None).unwrap();
+        db.set_user(Some(&user(10, "first"))).unwrap();
+        db.event(time(3610), Some(7), EventType::Started).unwrap();
+        db.update(7, time(3630), 20);
+        db.set_user(Some(&user(20, "second"))).unwrap();
+        db.update(7, time(3690), 5);
+        db.commit(time(3700)).unwrap();
+        db.set_user(Some(&user(10, "renamed"))).unwrap();
+        db.update(7, time(3750), 1);
+        db.flush(time(3800)).unwrap();
+
+        let mut stmt = db
+            .conn
+            .prepare(
+                "select account_name, value from timeline \
+                join users on timeline.user_id = users.user_id order by steam_id",
+            )
+            .unwrap();
+        let data = stmt
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .filter_map(Result::ok)
+            .collect::<Vec<(String, u32)>>();
+        assert_eq!(
+            data,
+            vec![("renamed".to_owned(), 21), ("second".to_owned(), 5)]
+        );
+        drop(stmt);
+
+        let users: Vec<u32> = db
+            .conn
+            .prepare("select user_id from events where object_id = 2 order by rowid")
+            .unwrap()
+            .query_map((), |row| row.get(0))
+            .unwrap()
+            .filter_map(Result::ok)
+            .collect();
+        assert_eq!(users, vec![1, 1]);
+
+        db.update(7, time(10000), 3000);
+        db.set_user(Some(&user(20, "second"))).unwrap();
+        db.update(7, time(10700), 3000);
+        db.commit(time(10700)).unwrap();
+        let total: u64 = db
+            .conn
+            .query_row(
+                "select sum(value) from timeline where timestamp = 2",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(total, 3600);
+    }
+
+    #[test]
+    fn playtime() {
+        let mut db = DeckDB::build(":memory:", time(3600), None).unwrap();
+        db.update(1, time(7000), 100);
+        db.update(2, time(7000), 10);
+        db.commit(time(7200)).unwrap();
+        db.update(1, time(7300), 50);
+        db.update(1, time(7400), 5);
+
+        assert_eq!(db.playtime(Some(1), time(0)).unwrap(), 155);
+        assert_eq!(db.playtime(None, time(3600)).unwrap(), 165);
+        assert_eq!(db.playtime(None, time(7200)).unwrap(), 55);
+        assert_eq!(db.playtime(None, time(5400)).unwrap(), 110);
+        assert_eq!(db.playtime(None, time(9000)).unwrap(), 27);
+        assert_eq!(db.playtime(Some(3), time(0)).unwrap(), 0);
+
+        db.event(time(7300), Some(1), EventType::LimitReached)
+            .unwrap();
+        let limits: Vec<(u64, u32)> = db
+            .conn
+            .prepare(
+                "select timestamp, app_id from events \
+                join objects on events.object_id = objects.object_id where event_type = ?1",
+            )
+            .unwrap()
+            .query_map((EventType::LimitReached as u32,), |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(limits, vec![(7300, 1)]);
+        db.flush(time(7300)).unwrap();
+    }
+
+    #[test]
+    fn spans() {
+        let mut db = DeckDB::build(":memory:", time(3000), None).unwrap();
+        // A tick from 3500 to 3700 is split at the boundary at 3600.
+        db.update(1, time(3700), 200);
+        // Playtime never exceeds the bucket length.
+        db.update(2, time(7200), 3600);
+        db.update(2, time(7200), 100);
+        db.flush(time(7300)).unwrap();
+
+        let data = db
+            .conn
+            .prepare(
+                "select timestamp, app_id, value from timeline \
+                join objects on timeline.object_id = objects.object_id order by 1, 2",
+            )
+            .unwrap()
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<Vec<(u64, u32, u64)>>>()
+            .unwrap();
+        assert_eq!(data, vec![(0, 1, 100), (1, 1, 100), (1, 2, 3600)]);
+    }
+
+    #[test]
+    fn boundaries() {
+        let mut db = DeckDB::build(":memory:", time(3000), None).unwrap();
+        // Ticks spanning the boundary at 3600 before the next commit.
+        db.update(1, time(3650), 100);
+        db.update(2, time(3650), 100);
+        db.update(1, time(3700), 50);
+        db.commit(time(3700)).unwrap();
+        // A stall spanning the boundary at 7200, committed after it.
+        db.update(1, time(7300), 3600);
+        db.update(2, time(7300), 3600);
+        db.update(2, time(7300), 100);
+        db.update(1, time(7200), 200);
+        db.commit(time(7300)).unwrap();
+        assert_eq!(db.playtime(Some(1), time(3600)).unwrap(), 3700);
+        db.update(1, time(11000), 60);
+        db.flush(time(11000)).unwrap();
+
+        let data = db
+            .conn
+            .prepare(
+                "select timestamp, app_id, value from timeline \
+                join objects on timeline.object_id = objects.object_id order by 1, 2",
+            )
+            .unwrap()
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<Vec<(u64, u32, u64)>>>()
+            .unwrap();
+        assert_eq!(
+            data,
+            vec![
+                (0, 1, 50),
+                (0, 2, 50),
+                (1, 1, 3600),
+                (1, 2, 3550),
+                (2, 1, 100),
+                (2, 2, 200),
+                (3, 1, 60)
+            ]
+        );
+    }
+
+    #[test]
+    fn buckets() {
+        let path = std::env::temp_dir().join(format!("decktime-buckets-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut db = DeckDB::build(path, time(900), Some(900)).unwrap();
+        db.update(1, time(1700), 100);
+        db.commit(time(1800)).unwrap();
+        db.update(1, time(1860), 60);
+        assert_eq!(db.playtime(Some(1), time(1350)).unwrap(), 110);
+        db.flush(time(1900)).unwrap();
+        drop(db);
+
+        let mut db = DeckDB::build(path, time(2000), Some(3600)).unwrap();
+        assert_eq!(db.bucket_secs, 900);
+        let data = db
+            .conn
+            .prepare("select timestamp, value from timeline order by timestamp")
+            .unwrap()
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<Vec<(u64, u64)>>>()
+            .unwrap();
+        assert_eq!(data, vec![(1, 100), (2, 60)]);
+        db.flush(time(2000)).unwrap();
+        drop(db);
+        let _ = fs::remove_file(path);
+    }
 }
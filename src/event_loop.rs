@@ -0,0 +1,143 @@
+use log::debug;
+use std::{
+    cmp, io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+    time::{Duration, SystemTime},
+};
+
+/// Waits for the next scheduler tick, readable or writable file descriptors
+/// and signals on the main thread.
+///
+/// The signals are blocked and read from a signalfd instead, which must
+/// happen before any other thread is started. Commands spawned later get
+/// an empty signal mask from `std::process::Command`.
+pub struct EventLoop {
+    signal_fd: OwnedFd,
+    /// Longest single sleep, so that changes of the wall clock are noticed.
+    max_sleep: Duration,
+}
+
+impl EventLoop {
+    pub fn build(signals: &[libc::c_int], max_sleep: Duration) -> io::Result<EventLoop> {
+        let fd = unsafe {
+            let mut mask: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut mask);
+            for &signal in signals {
+                libc::sigaddset(&mut mask, signal);
+            }
+            let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &mask, ptr::null_mut());
+            if ret != 0 {
+                return Err(io::Error::from_raw_os_error(ret));
+            }
+            libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventLoop {
+            signal_fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            max_sleep,
+        })
+    }
+
+    fn read_signal(&self) -> io::Result<Option<libc::c_int>> {
+        let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+        let size = mem::size_of::<libc::signalfd_siginfo>();
+        let ret = unsafe {
+            libc::read(
+                self.signal_fd.as_raw_fd(),
+                &mut info as *mut _ as *mut libc::c_void,
+                size,
+            )
+        };
+        match ret {
+            ret if ret == size as isize => Ok(Some(info.ssi_signo as libc::c_int)),
+            _ => match io::Error::last_os_error() {
+                err if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+                err => Err(err),
+            },
+        }
+    }
+
+    /// Sleeps until `until`, until one of `fds` becomes readable, one of
+    /// `write_fds` becomes writable or until one of the signals arrives, which
+    /// is returned.
+    pub fn wait(
+        &self,
+        until: SystemTime,
+        fds: &[RawFd],
+        write_fds: &[RawFd],
+    ) -> io::Result<Option<libc::c_int>> {
+        let pollfd = |events| {
+            move |&fd| libc::pollfd {
+                fd,
+                events,
+                revents: 0,
+            }
+        };
+        let mut pollfds: Vec<libc::pollfd> = [self.signal_fd.as_raw_fd()]
+            .iter()
+            .chain(fds)
+            .map(pollfd(libc::POLLIN))
+            .chain(write_fds.iter().map(pollfd(libc::POLLOUT)))
+            .collect();
+        loop {
+            let Ok(duration) = until.duration_since(SystemTime::now()) else {
+                return Ok(None);
+            };
+            let timeout = cmp::min(duration, self.max_sleep)
+                .as_nanos()
+                .div_ceil(1_000_000) as libc::c_int;
+            let ret =
+                unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout) };
+            match ret {
+                0 => continue,
+                ret if ret > 0 && pollfds[0].revents != 0 => return self.read_signal(),
+                ret if ret > 0 => return Ok(None),
+                _ => match io::Error::last_os_error() {
+                    err if err.kind() == io::ErrorKind::Interrupted => {
+                        debug!("poll interrupted");
+                    }
+                    err => return Err(err),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, os::unix::net::UnixStream, time::Instant};
+
+    #[test]
+    fn wakeups() {
+        let event_loop = EventLoop::build(&[libc::SIGUSR2], Duration::from_millis(20)).unwrap();
+        let (mut writer, reader) = UnixStream::pair().unwrap();
+        let fds = [reader.as_raw_fd()];
+
+        let started = Instant::now();
+        let until = SystemTime::now() + Duration::from_millis(100);
+        assert_eq!(event_loop.wait(until, &fds, &[]).unwrap(), None);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        writer.write_all(b"x").unwrap();
+        let started = Instant::now();
+        let until = SystemTime::now() + Duration::from_secs(10);
+        assert_eq!(event_loop.wait(until, &fds, &[]).unwrap(), None);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // An empty socket buffer is writable right away.
+        let started = Instant::now();
+        let write_fds = [writer.as_raw_fd()];
+        assert_eq!(event_loop.wait(until, &[], &write_fds).unwrap(), None);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        unsafe { libc::pthread_kill(libc::pthread_self(), libc::SIGUSR2) };
+        assert_eq!(
+            event_loop.wait(until, &[], &[]).unwrap(),
+            Some(libc::SIGUSR2)
+        );
+    }
+}
@@ -0,0 +1,479 @@
+use crate::{
+    db::{AppId, DeckDB, EventType},
+    sessions,
+};
+use rusqlite::{Connection, Result};
+use std::fmt;
+
+/// Inconsistency found in the database.
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    /// Message of `PRAGMA integrity_check`, cannot be fixed.
+    Corrupt(String),
+    /// Row referencing an object or backup that does not exist, fixed by
+    /// deleting the row.
+    Orphan {
+        table: String,
+        rowid: i64,
+        parent: String,
+    },
+    /// Event missing from a session, fixed by inserting it at `timestamp`
+    /// right before the event with rowid `before`.
+    Missing {
+        app_id: AppId,
+        object_id: u32,
+        event_type: EventType,
+        timestamp: u64,
+        user_id: u32,
+        source: u32,
+        before: Option<i64>,
+    },
+    /// Event outside of a session, fixed by deleting it.
+    Stray {
+        app_id: AppId,
+        rowid: i64,
+        timestamp: u64,
+        event_type: EventType,
+    },
+    /// More playtime in a bucket than the bucket is long, fixed by clamping.
+    Overfull {
+        app_id: AppId,
+        object_id: u32,
+        bucket: u64,
+        secs: u64,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Corrupt(message) => write!(f, "integrity check: {message}"),
+            Problem::Orphan {
+                table,
+                rowid,
+                parent,
+            } => write!(f, "{table} row {rowid} references a missing {parent} row"),
+            Problem::Missing {
+                app_id,
+                event_type,
+                timestamp,
+                ..
+            } => write!(
+                f,
+                "app_id={app_id}: missing {} event at {}",
+                event_type.name(),
+                sessions::format_timestamp(*timestamp)
+            ),
+            Problem::Stray {
+                app_id,
+                timestamp,
+                event_type,
+                ..
+            } => write!(
+                f,
+                "app_id={app_id}: {} event at {} outside of a session",
+                event_type.name(),
+                sessions::format_timestamp(*timestamp)
+            ),
+            Problem::Overfull {
+                app_id,
+                bucket,
+                secs,
+                ..
+            } => write!(f, "app_id={app_id}: {secs}s of playtime in bucket {bucket}"),
+        }
+    }
+}
+
+fn event_type(value: u32) -> Option<EventType> {
+    [
+        EventType::Started,
+        EventType::Stopped,
+        EventType::Suspended,
+        EventType::Resumed,
+    ]
+    .into_iter()
+    .find(|&event_type| event_type as u32 == value)
+}
+
+struct Row {
+    rowid: i64,
+    timestamp: u64,
+    event_type: EventType,
+    user_id: u32,
+    source: u32,
+}
+
+/// Walks the session events of one object, `live` objects have a `Running`
+/// marker and are allowed to end with an open session.
+fn check_events(app_id: AppId, object_id: u32, rows: &[Row], live: bool) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    // Started event of the open session and the time it was suspended at.
+    let mut open: Option<&Row> = None;
+    let mut suspended: Option<u64> = None;
+    let mut last = 0;
+
+    let missing = |started: &Row, event_type, timestamp, before| Problem::Missing {
+        app_id,
+        object_id,
+        event_type,
+        timestamp,
+        user_id: started.user_id,
+        source: started.source,
+        before,
+    };
+    let stray = |row: &Row| Problem::Stray {
+        app_id,
+        rowid: row.rowid,
+        timestamp: row.timestamp,
+        event_type: row.event_type,
+    };
+    // Closes the open session at the last event seen.
+    let close = |problems: &mut Vec<Problem>, started: &Row, suspended: Option<u64>, last| {
+        if suspended.is_some() {
+            problems.push(missing(started, EventType::Resumed, last, None));
+        }
+        problems.push(missing(started, EventType::Stopped, last, None));
+    };
+
+    for row in rows {
+        match (row.event_type, open) {
+            (EventType::Started, None) => open = Some(row),
+            (EventType::Started, Some(started)) if row.timestamp > last => {
+                close(&mut problems, started, suspended.take(), last);
+                open = Some(row);
+            }
+            (EventType::Started, Some(_)) => {
+                problems.push(stray(row));
+                continue;
+            }
+            (EventType::Stopped | EventType::Suspended, Some(started)) if suspended.is_some() => {
+                problems.push(missing(
+                    started,
+                    EventType::Resumed,
+                    row.timestamp,
+                    Some(row.rowid),
+                ));
+                suspended = None;
+                if let EventType::Stopped = row.event_type {
+                    open = None;
+                } else {
+                    suspended = Some(row.timestamp);
+                }
+            }
+            (EventType::Stopped, Some(_)) => open = None,
+            (EventType::Suspended, Some(_)) => suspended = Some(row.timestamp),
+            (EventType::Resumed, Some(_)) => suspended = None,
+            (_, None) => {
+                problems.push(stray(row));
+                continue;
+            }
+            _ => {}
+        }
+        last = row.timestamp;
+    }
+    if let Some(started) = open.filter(|_| !live) {
+        close(&mut problems, started, suspended, last);
+    }
+    problems
+}
+
+/// Runs `PRAGMA integrity_check` and the semantic checks of events and
+/// playtime.
+pub fn check(conn: &Connection) -> Result<Vec<Problem>> {
+    let mut problems: Vec<Problem> = conn
+        .prepare("pragma integrity_check")?
+        .query_map((), |row| row.get::<_, String>(0))?
+        .filter(|message| !matches!(message.as_deref(), Ok("ok")))
+        .map(|message| message.map(Problem::Corrupt))
+        .collect::<Result<_>>()?;
+
+    let mut stmt = conn.prepare("pragma foreign_key_check")?;
+    let mut rows = stmt.query(())?;
+    while let Some(row) = rows.next()? {
+        problems.push(Problem::Orphan {
+            table: row.get(0)?,
+            rowid: row.get(1)?,
+            parent: row.get(2)?,
+        });
+    }
+
+    let objects: Vec<(u32, AppId, bool)> = conn
+        .prepare(
+            "select object_id, app_id, exists ( \
+                    select * from events where events.object_id = objects.object_id \
+                    and event_type = ?1) \
+                from objects order by app_id",
+        )?
+        .query_map((EventType::Running as u32,), |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?
+        .collect::<Result<_>>()?;
+    let mut stmt = conn.prepare(
+        "select rowid, timestamp, event_type, user_id, source from events \
+            where object_id = ?1 order by timestamp, rowid",
+    )?;
+    for (object_id, app_id, live) in objects {
+        let rows: Vec<Row> = stmt
+            .query_map((object_id,), |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get::<_, u32>(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })?
+            .filter_map(|row| match row {
+                Ok((rowid, timestamp, event_type_id, user_id, source)) => event_type(event_type_id)
+                    .map(|event_type| {
+                        Ok(Row {
+                            rowid,
+                            timestamp,
+                            event_type,
+                            user_id,
+                            source,
+                        })
+                    }),
+                Err(err) => Some(Err(err)),
+            })
+            .collect::<Result<_>>()?;
+        problems.extend(check_events(app_id, object_id, &rows, live));
+    }
+
+    let bucket_secs = DeckDB::bucket_secs(conn)?;
+    let mut stmt = conn.prepare(
+        "select app_id, objects.object_id, timestamp, sum(value) from timeline \
+            join objects on timeline.object_id = objects.object_id \
+            group by timestamp, objects.object_id having sum(value) > ?1 \
+            order by app_id, timestamp",
+    )?;
+    let overfull = stmt.query_map((bucket_secs,), |row| {
+        Ok(Problem::Overfull {
+            app_id: row.get(0)?,
+            object_id: row.get(1)?,
+            bucket: row.get(2)?,
+            secs: row.get(3)?,
+        })
+    })?;
+    for problem in overfull {
+        problems.push(problem?);
+    }
+
+    Ok(problems)
+}
+
+/// Fixes `problem`, returns false if it cannot be fixed.
+pub fn fix(conn: &Connection, problem: &Problem) -> Result<bool> {
+    match problem {
+        Problem::Corrupt(_) => return Ok(false),
+        Problem::Orphan { table, rowid, .. } => {
+            conn.execute(
+                &format!(
+                    "delete from \"{}\" where rowid = ?1",
+                    table.replace('"', "\"\"")
+                ),
+                (rowid,),
+            )?;
+        }
+        Problem::Missing {
+            object_id,
+            event_type,
+            timestamp,
+            user_id,
+            source,
+            before,
+            ..
+        } => {
+            conn.execute(
+                "insert into events (timestamp, object_id, event_type, user_id, source) \
+                    values (?1, ?2, ?3, ?4, ?5)",
+                (timestamp, object_id, *event_type as u32, user_id, source),
+            )?;
+            // Moves the next event behind the inserted one, the order of
+            // events with the same timestamp is the order of their rowids.
+            if let Some(before) = before {
+                conn.execute(
+                    "update events set rowid = (select max(rowid) + 1 from events) \
+                        where rowid = ?1 and timestamp = ?2",
+                    (before, timestamp),
+                )?;
+            }
+        }
+        Problem::Stray { rowid, .. } => {
+            conn.execute("delete from events where rowid = ?1", (rowid,))?;
+        }
+        Problem::Overfull {
+            object_id,
+            bucket,
+            secs,
+            ..
+        } => {
+            let rows: Vec<(u32, u32, u64)> = conn
+                .prepare(
+                    "select user_id, source, value from timeline \
+                        where timestamp = ?1 and object_id = ?2 order by value desc",
+                )?
+                .query_map((bucket, object_id), |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })?
+                .collect::<Result<_>>()?;
+            let mut excess = secs.saturating_sub(DeckDB::bucket_secs(conn)?);
+            for (user_id, source, value) in rows {
+                let cut = excess.min(value);
+                sessions::subtract_playtime(conn, *bucket, *object_id, user_id, source, cut)?;
+                excess -= cut;
+            }
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use EventType::*;
+
+    fn insert_events(conn: &Connection, app_id: AppId, events: &[(u64, EventType)]) {
+        let object_id = DeckDB::get_object_id(conn, app_id).unwrap();
+        for &(timestamp, event_type) in events {
+            conn.execute(
+                "insert into events (timestamp, object_id, event_type) values (?1, ?2, ?3)",
+                (timestamp, object_id, event_type as u32),
+            )
+            .unwrap();
+        }
+    }
+
+    fn events(conn: &Connection, app_id: AppId) -> Vec<(u64, u32)> {
+        conn.prepare(
+            "select timestamp, event_type from events \
+                join objects on events.object_id = objects.object_id \
+                where app_id = ?1 order by timestamp, events.rowid",
+        )
+        .unwrap()
+        .query_map((app_id,), |row| Ok((row.get(0)?, row.get(1)?)))
+        .unwrap()
+        .collect::<Result<_>>()
+        .unwrap()
+    }
+
+    #[test]
+    fn broken_events() {
+        let conn = DeckDB::open(":memory:").unwrap();
+        // Crashed without a Running marker and started again.
+        insert_events(
+            &conn,
+            10,
+            &[(100, Started), (150, Suspended), (300, Started)],
+        );
+        // Stopped while suspended, then a duplicated Started and a stray Resumed.
+        insert_events(
+            &conn,
+            20,
+            &[
+                (100, Started),
+                (120, Suspended),
+                (200, Stopped),
+                (200, Resumed),
+                (300, Started),
+                (300, Started),
+            ],
+        );
+        // Still running.
+        insert_events(&conn, 30, &[(100, Started), (200, Running)]);
+
+        let problems = check(&conn).unwrap();
+        assert_eq!(
+            problems
+                .iter()
+                .map(|problem| match problem {
+                    Problem::Missing {
+                        app_id,
+                        event_type,
+                        timestamp,
+                        ..
+                    } => (*app_id, "missing", *event_type as u32, *timestamp),
+                    Problem::Stray {
+                        app_id,
+                        event_type,
+                        timestamp,
+                        ..
+                    } => (*app_id, "stray", *event_type as u32, *timestamp),
+                    problem => panic!("unexpected {problem}"),
+                })
+                .collect::<Vec<_>>(),
+            vec![
+                (10, "missing", Resumed as u32, 150),
+                (10, "missing", Stopped as u32, 150),
+                (10, "missing", Stopped as u32, 300),
+                (20, "missing", Resumed as u32, 200),
+                (20, "stray", Resumed as u32, 200),
+                (20, "stray", Started as u32, 300),
+                (20, "missing", Stopped as u32, 300),
+            ]
+        );
+
+        for problem in problems.iter() {
+            assert!(fix(&conn, problem).unwrap());
+        }
+        assert_eq!(check(&conn).unwrap(), vec![]);
+        assert_eq!(
+            events(&conn, 20),
+            vec![
+                (100, Started as u32),
+                (120, Suspended as u32),
+                (200, Resumed as u32),
+                (200, Stopped as u32),
+                (300, Started as u32),
+                (300, Stopped as u32),
+            ]
+        );
+    }
+
+    #[test]
+    fn broken_rows() {
+        let conn = DeckDB::open(":memory:").unwrap();
+        let object_id = DeckDB::get_object_id(&conn, 10).unwrap();
+        conn.execute_batch(&format!(
+            "pragma foreign_keys = off; \
+            insert into timeline (timestamp, object_id, user_id, source, value) values \
+                (1, {object_id}, 0, 0, 3000), (1, {object_id}, 1, 0, 1000), \
+                (2, {object_id}, 0, 0, 3600), (3, {object_id}, 0, 1, 4000), \
+                (1, 99, 0, 0, 60); \
+            insert into events (timestamp, object_id, event_type) values (100, 99, 1); \
+            pragma foreign_keys = on;"
+        ))
+        .unwrap();
+
+        let problems = check(&conn).unwrap();
+        assert_eq!(
+            problems
+                .iter()
+                .map(|problem| problem.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "events row 1 references a missing objects row",
+                "timeline row 5 references a missing objects row",
+                "app_id=10: 4000s of playtime in bucket 1",
+                "app_id=10: 4000s of playtime in bucket 3",
+            ]
+        );
+
+        for problem in problems.iter() {
+            assert!(fix(&conn, problem).unwrap());
+        }
+        assert_eq!(check(&conn).unwrap(), vec![]);
+        let timeline: Vec<(u64, u32, u64)> = conn
+            .prepare("select timestamp, user_id, value from timeline order by timestamp, user_id")
+            .unwrap()
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            timeline,
+            vec![(1, 0, 2600), (1, 1, 1000), (2, 0, 3600), (3, 0, 3600)]
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{config, db, runner};
+use serde::Deserialize;
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    Started,
+    Stopped,
+    Suspended,
+    Resumed,
+    LimitReached,
+    TrackingPaused,
+    TrackingResumed,
+}
+
+impl HookEvent {
+    /// Whether the event belongs to an app rather than to decktime.
+    fn has_app(self) -> bool {
+        matches!(
+            self,
+            HookEvent::Started | HookEvent::Stopped | HookEvent::LimitReached
+        )
+    }
+
+    fn matches(self, event_type: db::EventType) -> bool {
+        matches!(
+            (self, event_type),
+            (HookEvent::Started, db::EventType::Started)
+                | (HookEvent::Stopped, db::EventType::Stopped)
+                | (HookEvent::Suspended, db::EventType::Suspended)
+                | (HookEvent::Resumed, db::EventType::Resumed)
+                | (HookEvent::LimitReached, db::EventType::LimitReached)
+                | (HookEvent::TrackingPaused, db::EventType::TrackingPaused)
+                | (HookEvent::TrackingResumed, db::EventType::TrackingResumed)
+        )
+    }
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Command executed in the background when one of `events` happens.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    pub events: Vec<HookEvent>,
+    /// Only run for this app, for every app if not set.
+    pub app: Option<db::AppId>,
+    pub command: String,
+    #[serde(default = "default_timeout")]
+    #[serde(deserialize_with = "config::deserialize_duration")]
+    pub timeout: Duration,
+}
+
+impl Hook {
+    /// Rejects an `app` filter on events that do not belong to an app.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.events.iter().find(|event| !event.has_app()) {
+            Some(event) if self.app.is_some() => {
+                Err(format!("app cannot be set for {event:?} events"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn matches(&self, event: &db::Event) -> bool {
+        if !self.events.iter().any(|e| e.matches(event.event_type)) {
+            return false;
+        }
+        match event.event_type {
+            db::EventType::Suspended
+            | db::EventType::Resumed
+            | db::EventType::TrackingPaused
+            | db::EventType::TrackingResumed => true,
+            _ => event.app_id.is_some() && self.app.is_none_or(|app| Some(app) == event.app_id),
+        }
+    }
+}
+
+fn environment(event: &db::Event) -> [(&'static str, String); 5] {
+    [
+        (
+            "DECKTIME_APP_ID",
+            event.app_id.map(|id| id.to_string()).unwrap_or_default(),
+        ),
+        ("DECKTIME_EVENT", event.event_type.name().to_owned()),
+        ("DECKTIME_ALIAS", event.alias.clone().unwrap_or_default()),
+        (
+            "DECKTIME_SESSION_SECS",
+            event
+                .session
+                .map(|session| session.as_secs().to_string())
+                .unwrap_or_default(),
+        ),
+        (
+            "DECKTIME_TIMESTAMP",
+            event
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .map(|ts| ts.as_secs().to_string())
+                .unwrap_or_default(),
+        ),
+    ]
+}
+
+pub fn get_hooks_listener(
+    ref_hooks: Rc<RefCell<Vec<Hook>>>,
+    ref_runner: Rc<RefCell<runner::Runner>>,
+) -> db::Listener {
+    Box::new(move |event| {
+        for hook in ref_hooks.borrow().iter().filter(|hook| hook.matches(event)) {
+            ref_runner
+                .borrow_mut()
+                .spawn(&hook.command, &environment(event), hook.timeout);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches() {
+        let config: config::Config = toml::from_str(
+            "[[hook]]\n\
+            events = [\"started\", \"stopped\"]\n\
+            command = \"true\"\n\
+            [[hook]]\n\
+            events = [\"suspended\"]\n\
+            command = \"true\"\n\
+            timeout = \"5s\"\n",
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let [games, suspend] = &config.hooks[..] else {
+            panic!("expected two hooks");
+        };
+        assert_eq!(games.timeout, Duration::from_secs(30));
+        assert_eq!(suspend.timeout, Duration::from_secs(5));
+
+        let event = |app_id, event_type| db::Event {
+            timestamp: UNIX_EPOCH + Duration::from_secs(1000),
+            app_id,
+            alias: Some("Dota 2".to_owned()),
+            event_type,
+            session: Some(Duration::from_secs(90)),
+        };
+        assert!(games.matches(&event(Some(1), db::EventType::Started)));
+        assert!(!games.matches(&event(None, db::EventType::Stopped)));
+        assert!(!games.matches(&event(Some(1), db::EventType::Running)));
+        assert!(suspend.matches(&event(None, db::EventType::Suspended)));
+
+        let config: config::Config = toml::from_str(
+            "[[hook]]\n\
+            events = [\"stopped\", \"suspended\"]\n\
+            app = 570\n\
+            command = \"true\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.validate(),
+            Err("hook #1 (\"true\"): app cannot be set for Suspended events".to_owned())
+        );
+
+        let env = environment(&event(Some(570), db::EventType::Stopped));
+        assert_eq!(
+            env.map(|(_, value)| value),
+            ["570", "stopped", "Dota 2", "90", "1000"].map(str::to_owned)
+        );
+    }
+}
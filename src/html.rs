@@ -0,0 +1,325 @@
+use crate::{
+    db::{AppId, DeckDB},
+    report, sessions,
+};
+use chrono::{DateTime, Datelike, NaiveDate, Timelike, Weekday};
+use rusqlite::{Connection, Result};
+use std::{collections::HashMap, fmt::Write as _};
+
+const COLORS: [&str; 8] = [
+    "#4e79a7", "#f28e2b", "#e15759", "#76b7b2", "#59a14f", "#edc948", "#b07aa1", "#9c755f",
+];
+const TIMELINE_DAYS: i64 = 14;
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn hours(secs: u64) -> String {
+    format!("{:.1}h", secs as f64 / 3600.0)
+}
+
+fn local(timestamp: u64, utc_offset: i32) -> DateTime<chrono::Utc> {
+    DateTime::from_timestamp(timestamp as i64 + utc_offset as i64, 0).unwrap_or_default()
+}
+
+fn daily_chart(out: &mut String, days: &[(NaiveDate, u64)]) {
+    let (Some(&(first, _)), Some(&(last, _))) = (days.first(), days.last()) else {
+        out.push_str("<p>no playtime recorded</p>\n");
+        return;
+    };
+    let values: HashMap<NaiveDate, u64> = days.iter().copied().collect();
+    let count = (last - first).num_days() as usize + 1;
+    let max = days
+        .iter()
+        .map(|&(_, secs)| secs)
+        .max()
+        .unwrap_or(0)
+        .max(3600);
+    let (bar, height) = (12, 160);
+    let width = count * bar + 60;
+
+    let _ = writeln!(out, "<svg width=\"{width}\" height=\"{}\">", height + 40);
+    let _ = writeln!(
+        out,
+        "<text x=\"0\" y=\"12\">{}</text><line x1=\"50\" y1=\"{height}\" x2=\"{width}\" y2=\"{height}\" stroke=\"#999\"/>",
+        hours(max)
+    );
+    for (i, date) in first.iter_days().take(count).enumerate() {
+        let secs = values.get(&date).copied().unwrap_or(0);
+        let h = (secs * height as u64 / max) as usize;
+        let x = 50 + i * bar;
+        let _ = writeln!(
+            out,
+            "<rect x=\"{x}\" y=\"{}\" width=\"{}\" height=\"{h}\" fill=\"{}\"><title>{date}: {}</title></rect>",
+            height - h,
+            bar - 2,
+            COLORS[0],
+            report::format_duration(secs)
+        );
+        if i % 7 == 0 {
+            let _ = writeln!(
+                out,
+                "<text x=\"{x}\" y=\"{}\">{}</text>",
+                height + 16,
+                date.format("%m-%d")
+            );
+        }
+    }
+    out.push_str("</svg>\n");
+}
+
+fn games_chart(out: &mut String, totals: &[report::AppTotal], colors: &HashMap<AppId, &str>) {
+    let max = totals
+        .iter()
+        .map(|total| total.seconds)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let (row, width) = (22, 400);
+    let _ = writeln!(
+        out,
+        "<svg width=\"{}\" height=\"{}\">",
+        width + 320,
+        totals.len() * row + 4
+    );
+    for (i, total) in totals.iter().enumerate() {
+        let y = i * row;
+        let name = match &total.alias {
+            Some(alias) => escape(alias),
+            None => total.app_id.to_string(),
+        };
+        let w = (total.seconds * width as u64 / max).max(1);
+        let _ = writeln!(
+            out,
+            "<text x=\"0\" y=\"{}\">{name}</text>\
+            <rect x=\"200\" y=\"{y}\" width=\"{w}\" height=\"{}\" fill=\"{}\"/>\
+            <text x=\"{}\" y=\"{}\">{}</text>",
+            y + 15,
+            row - 4,
+            colors.get(&total.app_id).unwrap_or(&COLORS[0]),
+            206 + w,
+            y + 15,
+            report::format_duration(total.seconds)
+        );
+    }
+    out.push_str("</svg>\n");
+}
+
+fn heatmap(out: &mut String, cells: &[[u64; 24]; 7]) {
+    let max = cells.iter().flatten().copied().max().unwrap_or(0).max(1);
+    let cell = 22;
+    let _ = writeln!(
+        out,
+        "<svg width=\"{}\" height=\"{}\">",
+        40 + 24 * cell,
+        20 + 7 * cell
+    );
+    for hour in (0..24).step_by(3) {
+        let _ = write!(
+            out,
+            "<text x=\"{}\" y=\"12\">{hour:02}</text>",
+            40 + hour * cell
+        );
+    }
+    for (day, hours) in cells.iter().enumerate() {
+        let y = 16 + day * cell;
+        let weekday = Weekday::try_from(day as u8).unwrap_or(Weekday::Mon);
+        let _ = write!(out, "<text x=\"0\" y=\"{}\">{weekday}</text>", y + 15);
+        for (hour, &secs) in hours.iter().enumerate() {
+            let _ = write!(
+                out,
+                "<rect x=\"{}\" y=\"{y}\" width=\"{}\" height=\"{}\" fill=\"{}\" fill-opacity=\"{:.2}\">\
+                <title>{weekday} {hour:02}:00: {}</title></rect>",
+                40 + hour * cell,
+                cell - 2,
+                cell - 2,
+                COLORS[0],
+                0.05 + 0.95 * secs as f64 / max as f64,
+                report::format_duration(secs)
+            );
+        }
+        out.push('\n');
+    }
+    out.push_str("</svg>\n");
+}
+
+fn session_timeline(
+    out: &mut String,
+    app_sessions: &[sessions::AppSession],
+    colors: &HashMap<AppId, &str>,
+    names: &HashMap<AppId, String>,
+    now: u64,
+    utc_offset: &impl Fn(u64) -> i32,
+) {
+    let (row, width) = (20, 720);
+    let today = (now as i64 + utc_offset(now) as i64).div_euclid(86400);
+    let first_day = today - TIMELINE_DAYS + 1;
+
+    let _ = writeln!(
+        out,
+        "<svg width=\"{}\" height=\"{}\">",
+        width + 90,
+        TIMELINE_DAYS as usize * row + 20
+    );
+    for hour in (0..=24).step_by(6) {
+        let _ = write!(
+            out,
+            "<text x=\"{}\" y=\"12\">{hour:02}</text>",
+            80 + hour * width / 24
+        );
+    }
+    for day in 0..TIMELINE_DAYS {
+        let date = local((first_day + day) as u64 * 86400, 0).date_naive();
+        let _ = write!(
+            out,
+            "<text x=\"0\" y=\"{}\">{}</text>",
+            30 + day as usize * row,
+            date.format("%a %m-%d")
+        );
+    }
+    for (session, &(start, end)) in app_sessions
+        .iter()
+        .flat_map(|session| session.parts.iter().map(move |part| (session, part)))
+    {
+        let offset = utc_offset(start) as i64;
+        let (mut start, end) = (start as i64 + offset, end as i64 + offset);
+        while start < end {
+            let day = start.div_euclid(86400);
+            let next = end.min((day + 1) * 86400);
+            if (first_day..=today).contains(&day) {
+                let x = 80 + (start - day * 86400) as usize * width / 86400;
+                let w = ((next - start) as usize * width / 86400).max(1);
+                let _ = writeln!(
+                    out,
+                    "<rect x=\"{x}\" y=\"{}\" width=\"{w}\" height=\"{}\" fill=\"{}\"><title>{} {}: {}</title></rect>",
+                    18 + (day - first_day) as usize * row,
+                    row - 4,
+                    colors.get(&session.app_id).unwrap_or(&COLORS[0]),
+                    names[&session.app_id],
+                    local(session.start, utc_offset(session.start)).format("%Y-%m-%d %H:%M"),
+                    report::format_duration(session.secs)
+                );
+            }
+            start = next;
+        }
+    }
+    out.push_str("</svg>\n");
+}
+
+/// Renders a self-contained HTML page with inline SVG charts.
+pub fn render(conn: &Connection, now: u64, utc_offset: impl Fn(u64) -> i32) -> Result<String> {
+    let totals = report::app_totals(conn, None)?;
+    let days = report::daily_totals(conn, None, &utc_offset)?;
+    let app_sessions = sessions::app_sessions(conn, now)?;
+
+    let mut names: HashMap<AppId, String> = HashMap::new();
+    let mut stmt = conn.prepare("select app_id, alias from objects")?;
+    let mut rows = stmt.query(())?;
+    while let Some(row) = rows.next()? {
+        let app_id: AppId = row.get(0)?;
+        let alias: Option<String> = row.get(1)?;
+        names.insert(app_id, escape(&alias.unwrap_or_else(|| app_id.to_string())));
+    }
+    let colors: HashMap<AppId, &str> = totals
+        .iter()
+        .enumerate()
+        .map(|(i, total)| (total.app_id, COLORS[i % COLORS.len()]))
+        .collect();
+
+    let bucket_secs = DeckDB::bucket_secs(conn)?;
+    let mut cells = [[0u64; 24]; 7];
+    let mut stmt = conn.prepare(
+        "select timestamp, utc_offset, sum(value) from timeline group by timestamp, utc_offset",
+    )?;
+    let mut rows = stmt.query(())?;
+    while let Some(row) = rows.next()? {
+        let start = row.get::<_, u64>(0)? * bucket_secs;
+        let offset = row
+            .get::<_, Option<i32>>(1)?
+            .unwrap_or_else(|| utc_offset(start));
+        let value = row.get(2)?;
+        for (hour, secs) in report::split_hours(start as i64, bucket_secs as i64, offset, value) {
+            let time = DateTime::from_timestamp(hour * 3600, 0).unwrap_or_default();
+            cells[time.weekday().num_days_from_monday() as usize][time.hour() as usize] += secs;
+        }
+    }
+
+    let total: u64 = totals.iter().map(|total| total.seconds).sum();
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+        <title>decktime report</title>\n<style>\n\
+        body {{ font-family: sans-serif; margin: 2em; color: #222; background: #fafafa; }}\n\
+        svg {{ display: block; margin: 1em 0; }}\n\
+        svg text {{ font-size: 11px; fill: #444; }}\n\
+        </style>\n</head>\n<body>\n<h1>decktime report</h1>\n\
+        <p>Generated {}, {} played in total.</p>",
+        local(now, utc_offset(now)).format("%Y-%m-%d %H:%M"),
+        report::format_duration(total)
+    );
+    out.push_str("<h2>Hours per day</h2>\n");
+    daily_chart(&mut out, &days);
+    out.push_str("<h2>Games</h2>\n");
+    games_chart(&mut out, &totals, &colors);
+    out.push_str("<h2>Hour of day</h2>\n");
+    heatmap(&mut out, &cells);
+    let _ = writeln!(out, "<h2>Sessions of the last {TIMELINE_DAYS} days</h2>");
+    session_timeline(&mut out, &app_sessions, &colors, &names, now, &utc_offset);
+    out.push_str("</body>\n</html>\n");
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::UNKNOWN_USER_ID;
+
+    #[test]
+    fn self_contained() {
+        let conn = DeckDB::open(":memory:").unwrap();
+        let now = 20 * 86400;
+        sessions::add_session(&conn, 10, UNKNOWN_USER_ID, now - 7200, now - 3600, now).unwrap();
+        sessions::add_session(
+            &conn,
+            20,
+            UNKNOWN_USER_ID,
+            now - 86400 - 600,
+            now - 86400 + 600,
+            now,
+        )
+        .unwrap();
+        conn.execute(
+            "update objects set alias = '<Hades & co>' where app_id = 10",
+            (),
+        )
+        .unwrap();
+
+        let html = render(&conn, now, |_| 0).unwrap();
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert_eq!(html.matches("<svg").count(), 4);
+        assert!(html.contains("&lt;Hades &amp; co&gt;"));
+        assert!(!html.contains("<Hades"));
+        assert!(!html.contains("http"));
+        // The session crossing midnight is drawn on both days.
+        assert_eq!(html.matches("<title>20 1970-01-19 23:50").count(), 2);
+
+        // At 5:30 ahead an hour bucket covers half of two local hours.
+        conn.execute("update timeline set utc_offset = 19800", ())
+            .unwrap();
+        let html = render(&conn, now, |_| 19800).unwrap();
+        for title in [
+            "Wed 03:00: 0h 30m",
+            "Wed 04:00: 0h 30m",
+            "Tue 05:00: 0h 10m",
+        ] {
+            assert!(html.contains(&format!("<title>{title}</title>")), "{title}");
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{db::AppId, sessions};
+use chrono::DateTime;
+use rusqlite::{Connection, Result};
+use std::collections::HashMap;
+
+fn format_time(timestamp: u64) -> String {
+    DateTime::from_timestamp(timestamp as i64, 0)
+        .unwrap_or_default()
+        .format("%Y%m%dT%H%M%SZ")
+        .to_string()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Appends a content line folded at 75 octets as required by RFC 5545.
+fn push_line(out: &mut String, line: &str) {
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            len = 1;
+        }
+        out.push(c);
+        len += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+/// Exports finished sessions as an iCalendar, one event per part between
+/// suspends.
+///
+/// UIDs are derived from the app, the session id or start and the index of
+/// the part, so importing a newer export updates the events instead of
+/// duplicating them, also after the session was edited or trimmed.
+pub fn export(conn: &Connection, now: u64) -> Result<String> {
+    let aliases: HashMap<AppId, String> = conn
+        .prepare("select app_id, alias from objects where alias is not null")?
+        .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_>>()?;
+
+    let mut out = String::new();
+    push_line(&mut out, "BEGIN:VCALENDAR");
+    push_line(&mut out, "VERSION:2.0");
+    push_line(&mut out, "PRODID:-//decktime//decktime//EN");
+    push_line(&mut out, "CALSCALE:GREGORIAN");
+    for session in sessions::app_sessions(conn, now)? {
+        if session.running {
+            continue;
+        }
+        let summary = match aliases.get(&session.app_id) {
+            Some(alias) => escape(alias),
+            None => format!("App {}", session.app_id),
+        };
+        let count = session.parts.len();
+        for (i, &(start, end)) in session.parts.iter().enumerate() {
+            push_line(&mut out, "BEGIN:VEVENT");
+            let uid = match session.manual_id {
+                Some(id) => format!("{}-manual-{id}-{i}", session.app_id),
+                None => format!("{}-{}-{i}", session.app_id, session.started),
+            };
+            push_line(&mut out, &format!("UID:{uid}@decktime"));
+            push_line(&mut out, &format!("DTSTAMP:{}", format_time(now)));
+            push_line(&mut out, &format!("DTSTART:{}", format_time(start)));
+            push_line(&mut out, &format!("DTEND:{}", format_time(end)));
+            push_line(&mut out, &format!("SUMMARY:{summary}"));
+            if count > 1 {
+                push_line(&mut out, &format!("DESCRIPTION:Part {} of {count}", i + 1));
+            }
+            push_line(&mut out, "END:VEVENT");
+        }
+    }
+    push_line(&mut out, "END:VCALENDAR");
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{DeckDB, EventType, UNKNOWN_USER_ID};
+
+    #[test]
+    fn calendar() {
+        let conn = DeckDB::open(":memory:").unwrap();
+        let now = 86400;
+        sessions::add_session(&conn, 10, UNKNOWN_USER_ID, 3600, 7200, now).unwrap();
+        conn.execute(
+            "update objects set alias = 'Hades; the, game' where app_id = 10",
+            (),
+        )
+        .unwrap();
+        for (timestamp, event_type) in [
+            (5000, EventType::Suspended),
+            (6000, EventType::Resumed),
+            (80000, EventType::Started),
+        ] {
+            conn.execute(
+                "insert into events (timestamp, object_id, event_type) \
+                    select ?1, object_id, ?2 from objects where app_id = 10",
+                (timestamp, event_type as u32),
+            )
+            .unwrap();
+        }
+
+        let ics = export(&conn, now).unwrap();
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(ics.ends_with("END:VCALENDAR\r\n"));
+        assert_eq!(ics.matches("BEGIN:VEVENT").count(), 2);
+        assert!(ics.contains(
+            "UID:10-manual-1-0@decktime\r\nDTSTAMP:19700102T000000Z\r\n\
+            DTSTART:19700101T010000Z\r\nDTEND:19700101T012320Z\r\n\
+            SUMMARY:Hades\\; the\\, game\r\nDESCRIPTION:Part 1 of 2\r\n"
+        ));
+        assert!(ics.contains("UID:10-manual-1-1@decktime"));
+        // The running session is not exported.
+        assert!(!ics.contains("UID:10-80000-0@decktime"));
+        // Exporting again gives the same events.
+        assert_eq!(export(&conn, now).unwrap(), ics);
+
+        // Editing and trimming the session keeps its events.
+        let uids = |ics: &str| -> Vec<String> {
+            ics.lines()
+                .filter(|line| line.starts_with("UID:"))
+                .map(str::to_owned)
+                .collect()
+        };
+        sessions::edit_session(&conn, 1, None, Some(3000), None, now).unwrap();
+        conn.execute(
+            "insert into exclusions (start_ts, end_ts, seconds) values (3000, 3700, 700)",
+            (),
+        )
+        .unwrap();
+        let edited = export(&conn, now).unwrap();
+        assert!(edited.contains("DTSTART:19700101T010140Z"));
+        assert_eq!(uids(&edited), uids(&ics));
+
+        let mut folded = String::new();
+        push_line(&mut folded, &"ä".repeat(50));
+        assert!(folded.split("\r\n").all(|line| line.len() <= 75));
+        assert_eq!(
+            folded.replace("\r\n ", ""),
+            format!("{}\r\n", "ä".repeat(50))
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use crate::{config, db, observer, runner};
+use chrono::{DateTime, Datelike, Local, NaiveTime, TimeZone, Weekday};
+use log::{error, warn};
+use serde::Deserialize;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::Rc,
+    time::{Duration, SystemTime},
+};
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    #[default]
+    Day,
+    Week,
+}
+
+/// Soft playtime limit, e.g. "2h per day for app X".
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+    pub name: Option<String>,
+    /// Limited app, all apps together if not set.
+    pub app: Option<db::AppId>,
+    #[serde(default)]
+    pub period: Period,
+    #[serde(deserialize_with = "config::deserialize_duration")]
+    pub max: Duration,
+    /// Local weekdays the limit applies on, every day if empty.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub command: Option<String>,
+    /// Terminate the limited games once the limit is reached.
+    #[serde(default)]
+    pub kill: bool,
+}
+
+impl Limit {
+    fn describe(&self) -> String {
+        match (&self.name, self.app) {
+            (Some(name), _) => name.clone(),
+            (None, Some(app_id)) => format!("app_id={app_id}"),
+            (None, None) => "total".to_owned(),
+        }
+    }
+
+    fn applies_on(&self, weekday: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&weekday)
+    }
+
+    /// Local midnight the current day or week started at.
+    fn period_start<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> DateTime<Tz> {
+        let mut date = now.date_naive();
+        if self.period == Period::Week {
+            date = date - chrono::Days::new(now.weekday().num_days_from_monday() as u64);
+        }
+        now.timezone()
+            .from_local_datetime(&date.and_time(NaiveTime::MIN))
+            .earliest()
+            .unwrap_or_else(|| now.clone())
+    }
+}
+
+pub fn get_limits_func(
+    ref_limits: Rc<RefCell<Vec<Limit>>>,
+    ref_db: Rc<RefCell<db::DeckDB>>,
+    ref_tracker: Rc<RefCell<observer::Tracker>>,
+    ref_runner: Rc<RefCell<runner::Runner>>,
+) -> impl FnMut(SystemTime) {
+    let mut reached: HashMap<usize, SystemTime> = HashMap::new();
+
+    move |now| {
+        let local = DateTime::<Local>::from(now);
+        let running = ref_tracker.borrow().running_apps();
+
+        for (i, limit) in ref_limits.borrow().iter().enumerate() {
+            let active = match limit.app {
+                Some(app_id) => running.contains(&app_id),
+                None => !running.is_empty(),
+            };
+            if !active || !limit.applies_on(local.weekday()) {
+                continue;
+            }
+
+            let start = SystemTime::from(limit.period_start(&local));
+            let mut db = ref_db.borrow_mut();
+            let used = match db.playtime(limit.app, start) {
+                Ok(used) => Duration::from_secs(used),
+                Err(err) => {
+                    error!("unable to evaluate limit {}: {err}", limit.describe());
+                    continue;
+                }
+            };
+            if used < limit.max {
+                continue;
+            }
+            // Games relaunched after the limit are terminated again, but it
+            // is reported once per period.
+            if limit.kill {
+                ref_tracker.borrow().kill(limit.app);
+            }
+            if reached.insert(i, start) == Some(start) {
+                continue;
+            }
+
+            warn!(
+                "limit {} reached: played {}s of {}s",
+                limit.describe(),
+                used.as_secs(),
+                limit.max.as_secs()
+            );
+            db.event(now, limit.app, db::EventType::LimitReached)
+                .expect("event error");
+
+            if let Some(command) = &limit.command {
+                let envs = [
+                    ("DECKTIME_LIMIT", limit.describe()),
+                    (
+                        "DECKTIME_APP_ID",
+                        limit.app.map(|id| id.to_string()).unwrap_or_default(),
+                    ),
+                    ("DECKTIME_PLAYED_SECS", used.as_secs().to_string()),
+                    ("DECKTIME_MAX_SECS", limit.max.as_secs().to_string()),
+                ];
+                ref_runner
+                    .borrow_mut()
+                    .spawn(command, &envs, COMMAND_TIMEOUT);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use std::{
+        env, fs,
+        process::{Child, Command},
+        thread,
+    };
+
+    fn is_terminated(child: &mut Child) -> bool {
+        for _ in 0..200 {
+            if child.try_wait().unwrap().is_some() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        child.kill().unwrap();
+        false
+    }
+
+    #[test]
+    fn parse_and_period() {
+        let config: config::Config = toml::from_str(
+            "[[limit]]\n\
+            app = 570\n\
+            max = \"2h\"\n\
+            [[limit]]\n\
+            name = \"school nights\"\n\
+            period = \"week\"\n\
+            max = \"3h\"\n\
+            days = [\"Sun\", \"mon\", \"Tuesday\"]\n\
+            command = \"notify-send 'time is up'\"\n\
+            kill = true\n",
+        )
+        .unwrap();
+        let [daily, weekly] = &config.limits[..] else {
+            panic!("expected two limits");
+        };
+
+        assert_eq!(daily.app, Some(570));
+        assert_eq!(daily.max, Duration::from_secs(7200));
+        assert!(daily.applies_on(Weekday::Sat));
+        assert_eq!(weekly.describe(), "school nights");
+        assert!(weekly.applies_on(Weekday::Tue));
+        assert!(!weekly.applies_on(Weekday::Wed));
+
+        let now = Utc.with_ymd_and_hms(2025, 1, 16, 15, 30, 0).unwrap();
+        assert_eq!(
+            daily.period_start(&now),
+            Utc.with_ymd_and_hms(2025, 1, 16, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            weekly.period_start(&now),
+            Utc.with_ymd_and_hms(2025, 1, 13, 0, 0, 0).unwrap()
+        );
+
+        assert!(toml::from_str::<config::Config>("[[limit]]\nmax = \"1h\"\nfoo = 1\n").is_err());
+    }
+
+    #[test]
+    fn kill_relaunched() {
+        let proc_root = env::temp_dir().join(format!("decktime-limits-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&proc_root);
+        let steam = "/home/deck/.local/share/Steam/ubuntu12_32/steam";
+        observer::tests::add_process(&proc_root, 5000001, 1, steam, "steam");
+        let launch = || {
+            let child = Command::new("sleep").arg("60").spawn().unwrap();
+            let cmdline = "reaper SteamLaunch AppId=42 --";
+            observer::tests::add_process(&proc_root, child.id(), 5000001, "/game", cmdline);
+            child
+        };
+
+        let config: config::Config =
+            toml::from_str("[[limit]]\napp = 42\nmax = \"1s\"\nkill = true\n").unwrap();
+        let mut db = db::DeckDB::build(":memory:", SystemTime::UNIX_EPOCH, None).unwrap();
+        let events = observer::tests::record_events(&mut db);
+        let ref_db = Rc::new(RefCell::new(db));
+        let ref_tracker = Rc::new(RefCell::new(observer::Tracker::build(
+            observer::Monitor::Poll,
+            &proc_root,
+        )));
+        let mut update = observer::get_update_func(
+            Duration::from_secs(2),
+            Rc::clone(&ref_db),
+            Rc::clone(&ref_tracker),
+        );
+        let mut check = get_limits_func(
+            Rc::new(RefCell::new(config.limits)),
+            Rc::clone(&ref_db),
+            Rc::clone(&ref_tracker),
+            Rc::new(RefCell::new(runner::Runner::default())),
+        );
+
+        let now = SystemTime::now();
+        let mut game = launch();
+        update(now);
+        ref_db.borrow_mut().update(42, now, 1);
+        check(now);
+        assert!(is_terminated(&mut game));
+
+        fs::remove_dir_all(proc_root.join(game.id().to_string())).unwrap();
+        update(now);
+        let mut relaunched = launch();
+        update(now);
+        check(now);
+        assert!(is_terminated(&mut relaunched));
+        let limits = events
+            .borrow()
+            .iter()
+            .filter(|(_, event_type)| *event_type == db::EventType::LimitReached)
+            .count();
+        assert_eq!(limits, 1);
+
+        fs::remove_dir_all(proc_root.join(relaunched.id().to_string())).unwrap();
+        update(now);
+        ref_db.borrow_mut().flush(now).unwrap();
+        fs::remove_dir_all(&proc_root).unwrap();
+    }
+}
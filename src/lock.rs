@@ -0,0 +1,52 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+};
+
+/// Exclusive `flock` on `<db>.lock`, held by the daemon while it runs and by
+/// commands that must not change the database behind its back. Released when
+/// dropped or when the process exits.
+pub struct DbLock {
+    _file: File,
+}
+
+impl DbLock {
+    /// Takes the lock without waiting, fails with `WouldBlock` if it is held.
+    /// In-memory databases are not shared and need no lock.
+    pub fn acquire(db_path: &str) -> io::Result<Option<DbLock>> {
+        if db_path == ":memory:" {
+            return Ok(None);
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(format!("{db_path}.lock"))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(DbLock { _file: file }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    #[test]
+    fn exclusive() {
+        let path = env::temp_dir().join(format!("decktime-lock-{}.db", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert!(DbLock::acquire(":memory:").unwrap().is_none());
+
+        let lock = DbLock::acquire(path).unwrap();
+        assert!(lock.is_some());
+        let err = DbLock::acquire(path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        drop(lock);
+        assert!(DbLock::acquire(path).unwrap().is_some());
+        fs::remove_file(format!("{path}.lock")).unwrap();
+    }
+}
@@ -1,18 +1,41 @@
+mod backup;
+mod bundle;
+mod config;
+mod control;
 mod db;
+mod event_loop;
+mod fsck;
+mod hooks;
+mod html;
+mod ics;
+mod limits;
+mod lock;
+mod metrics;
+mod mqtt;
 mod observer;
+mod procmon;
+mod report;
+mod runner;
 mod schedule;
+mod sessions;
+mod stats;
+mod steam;
+mod tui;
 
-use clap::Parser;
-use log::{error, info};
+use chrono::{DateTime, Local, NaiveTime};
+use clap::{Parser, Subcommand};
+use log::{error, info, warn};
 use std::{
     cell::RefCell,
-    cmp,
+    io,
+    path::{Path, PathBuf},
     rc::Rc,
-    sync::{atomic, Arc},
-    thread,
-    time::{Duration, SystemTime},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+/// Delay of the backup at startup when the scheduled one was missed.
+const BACKUP_CATCH_UP_DELAY: Duration = Duration::from_secs(10 * 60);
+
 #[derive(Parser)]
 #[command(version = env!("CARGO_PKG_VERSION"))]
 struct Args {
@@ -27,6 +50,125 @@ struct Args {
     #[arg(short, default_value = "60", value_parser = parse_secs)]
     #[arg(value_name = "INTERVAL", help = "Commit interval in seconds")]
     commit_interval: Duration,
+
+    #[arg(short, value_parser = parse_bucket)]
+    #[arg(
+        value_name = "MINUTES",
+        help = "Timeline bucket size for new databases, must divide an hour [default: 60]"
+    )]
+    bucket_size: Option<u64>,
+
+    #[arg(short, value_enum, default_value = "netlink")]
+    #[arg(
+        value_name = "MONITOR",
+        help = "Process monitor, netlink falls back to poll"
+    )]
+    monitor: observer::Monitor,
+
+    #[arg(short = 'C')]
+    #[arg(value_name = "PATH", help = "Path to the config file")]
+    config_path: Option<PathBuf>,
+
+    #[arg(short)]
+    #[arg(
+        value_name = "PATH",
+        help = "Path to the control socket [default: $XDG_RUNTIME_DIR/decktime.sock]"
+    )]
+    socket_path: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print total playtime per app
+    Report {
+        #[arg(long, value_name = "USER")]
+        #[arg(help = "Only count playtime of this Steam account (id, account or persona name)")]
+        user: Option<String>,
+
+        #[arg(long, help = "Print total playtime per local day instead")]
+        daily: bool,
+    },
+    /// Print statistics like longest sessions, streaks and forgotten games
+    Stats {
+        #[arg(long, default_value = "30", value_name = "DAYS")]
+        #[arg(help = "List games not played for more than this many days as forgotten")]
+        forgotten_days: u64,
+
+        #[arg(long, help = "Print JSON instead of tables")]
+        json: bool,
+    },
+    /// Show a live dashboard of running apps, totals and sessions
+    Tui {
+        #[arg(long, default_value = "2", value_parser = parse_secs)]
+        #[arg(value_name = "INTERVAL", help = "Refresh interval in seconds")]
+        refresh: Duration,
+    },
+    /// Write a static HTML page with charts of the playtime
+    HtmlReport {
+        #[arg(long, default_value = "report.html", value_name = "FILE")]
+        #[arg(help = "Where to write the report")]
+        out: PathBuf,
+    },
+    /// Export finished sessions as an iCalendar file
+    ExportIcs {
+        #[arg(long, value_name = "FILE", help = "Write to a file instead of stdout")]
+        out: Option<PathBuf>,
+    },
+    /// Export the database as an encrypted and compressed bundle
+    ExportBundle {
+        #[arg(long, value_name = "FILE")]
+        out: PathBuf,
+        #[arg(long, value_name = "FILE", help = "Read the passphrase from a file")]
+        passphrase_file: Option<PathBuf>,
+    },
+    /// Remove the playtime of a time window, e.g. when someone else played
+    Exclude(sessions::Exclusion),
+    /// Log a session played elsewhere, e.g. on another device
+    AddSession(sessions::NewSession),
+    /// Change a manually logged session
+    EditSession(sessions::SessionChange),
+    /// Delete a manually logged session
+    DeleteSession {
+        #[arg(long, help = "Session id")]
+        id: u64,
+    },
+    /// List manually logged sessions
+    Sessions,
+    /// Check the database for inconsistencies
+    Fsck {
+        #[arg(long, help = "Fix what can be fixed, the daemon must not be running")]
+        fix: bool,
+    },
+    /// Replace the database with a backup, the daemon must not be running
+    Restore {
+        #[arg(value_name = "FILE", help = "Backup or encrypted bundle to restore")]
+        file: PathBuf,
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Read the bundle passphrase from a file"
+        )]
+        passphrase_file: Option<PathBuf>,
+    },
+    /// Show internals of the running daemon
+    Debug {
+        #[command(subcommand)]
+        target: DebugTarget,
+    },
+    /// Send a request to the running daemon
+    Ctl {
+        #[command(subcommand)]
+        request: control::Request,
+    },
+}
+
+#[derive(Subcommand)]
+enum DebugTarget {
+    /// Show run counts, callback durations and drift of the scheduled tasks
+    Scheduler,
 }
 
 fn parse_secs(s: &str) -> Result<Duration, String> {
@@ -36,57 +178,196 @@ fn parse_secs(s: &str) -> Result<Duration, String> {
     }
 }
 
-fn real_sleep(until: SystemTime, interval: Duration) -> SystemTime {
-    loop {
-        let now = SystemTime::now();
-        match until.duration_since(now) {
-            Ok(duration) => thread::sleep(cmp::min(duration, interval)),
-            Err(_) => return now,
-        }
+fn parse_bucket(s: &str) -> Result<u64, String> {
+    match s.parse::<u64>() {
+        Ok(val) if val > 0 && 60 % val == 0 => Ok(val * 60),
+        Ok(_) => Err("must divide an hour, e.g. 5, 15 or 60".to_owned()),
+        Err(err) => Err(err.to_string()),
     }
 }
 
-fn main() {
-    env_logger::builder().format_timestamp(None).init();
+fn run(args: &Args) {
     info!("version {}", env!("CARGO_PKG_VERSION"));
 
-    let args = Args::parse();
+    let event_loop =
+        event_loop::EventLoop::build(&[libc::SIGINT, libc::SIGTERM], args.update_interval)
+            .expect("signalfd error");
+
+    let config = match &args.config_path {
+        Some(path) => config::Config::load(path).expect("config error"),
+        None => config::Config::default(),
+    };
+
+    let _lock = match lock::DbLock::acquire(&args.db_path) {
+        Ok(lock) => lock,
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+            error!("the database is locked by another decktime process");
+            std::process::exit(1);
+        }
+        Err(err) => panic!("lock error: {err}"),
+    };
 
     let now = SystemTime::now();
-    let db = db::DeckDB::build(&args.db_path, now).expect("create db error");
+    let mut db = db::DeckDB::build(&args.db_path, now, args.bucket_size).expect("create db error");
+
+    let ref_runner = Rc::new(RefCell::new(runner::Runner::default()));
+    let ref_hooks = Rc::new(RefCell::new(config.hooks));
+    db.subscribe(hooks::get_hooks_listener(
+        Rc::clone(&ref_hooks),
+        Rc::clone(&ref_runner),
+    ));
+
+    let ref_mqtt = config.mqtt.map(|config| {
+        let ref_client = Rc::new(RefCell::new(mqtt::MqttClient::build(config)));
+        db.subscribe(mqtt::get_mqtt_listener(Rc::clone(&ref_client)));
+        ref_client
+    });
+
+    let mut exporter = config.metrics.and_then(|config| {
+        metrics::Exporter::bind(&config)
+            .inspect_err(|err| error!("unable to serve metrics on {}: {err}", config.listen))
+            .ok()
+    });
+
+    let mut control = socket_path(args).and_then(|path| {
+        control::Control::bind(&path)
+            .inspect_err(|err| error!("unable to listen on {path:?}: {err}"))
+            .ok()
+    });
 
     let ref_db = Rc::new(RefCell::new(db));
-    let mut sched = schedule::Scheduler::build_aligned(
-        vec![
-            (
-                args.commit_interval,
-                Box::new(observer::get_commit_func(Rc::clone(&ref_db))),
-            ),
-            (
-                args.update_interval,
-                Box::new(observer::get_update_func(
-                    args.update_interval.as_secs(),
-                    Rc::clone(&ref_db),
-                )),
-            ),
-            (
-                args.update_interval,
-                Box::new(observer::get_suspend_check_func(
-                    args.update_interval * 2,
-                    Rc::clone(&ref_db),
-                )),
+    let ref_tracker = Rc::new(RefCell::new(observer::Tracker::build(
+        args.monitor,
+        Path::new("/proc"),
+    )));
+    let ref_limits = Rc::new(RefCell::new(config.limits));
+    let every = schedule::Schedule::Every;
+    let mut tasks = vec![
+        schedule::Task::build(
+            "suspend_check",
+            every(args.update_interval),
+            Box::new(observer::get_suspend_check_func(
+                args.update_interval * 2,
+                Rc::clone(&ref_db),
+            )),
+        ),
+        schedule::Task::build(
+            "update",
+            every(args.update_interval),
+            Box::new(observer::get_update_func(
+                args.update_interval * 2,
+                Rc::clone(&ref_db),
+                Rc::clone(&ref_tracker),
+            )),
+        ),
+        schedule::Task::build(
+            "limits",
+            every(args.update_interval),
+            Box::new(limits::get_limits_func(
+                Rc::clone(&ref_limits),
+                Rc::clone(&ref_db),
+                Rc::clone(&ref_tracker),
+                Rc::clone(&ref_runner),
+            )),
+        ),
+        schedule::Task::build(
+            "commit",
+            every(args.commit_interval),
+            Box::new(observer::get_commit_func(Rc::clone(&ref_db))),
+        ),
+        schedule::Task::build(
+            "reap",
+            every(args.update_interval),
+            Box::new(runner::get_reap_func(Rc::clone(&ref_runner))),
+        ),
+    ];
+    if let Some(ref_client) = &ref_mqtt {
+        tasks.push(schedule::Task::build(
+            "mqtt",
+            every(args.update_interval),
+            Box::new(mqtt::get_mqtt_func(Rc::clone(ref_client))),
+        ));
+    }
+    match config.backup {
+        Some(_) if args.db_path == ":memory:" => warn!("backups need a database file"),
+        Some(config) => {
+            let backups = backup::Backups::build(config, &args.db_path);
+            let due = backups.is_due(DateTime::<Local>::from(now).date_naive());
+            let ref_backups = Rc::new(RefCell::new(backups));
+            tasks.push(
+                schedule::Task::build(
+                    "backup",
+                    schedule::Schedule::DailyAt(NaiveTime::from_hms_opt(3, 0, 0).unwrap()),
+                    Box::new(backup::get_backup_func(Rc::clone(&ref_backups))),
+                )
+                .jitter(Duration::from_secs(30 * 60)),
+            );
+            if due {
+                tasks.push(schedule::Task::build(
+                    "backup_catch_up",
+                    schedule::Schedule::Once(now + BACKUP_CATCH_UP_DELAY),
+                    Box::new(backup::get_backup_func(ref_backups)),
+                ));
+            }
+        }
+        None => {}
+    }
+    if let Some(home) = steam::SteamHome::from_env() {
+        tasks.insert(
+            0,
+            schedule::Task::build(
+                "user_check",
+                every(args.update_interval),
+                Box::new(observer::get_user_check_func(home, Rc::clone(&ref_db))),
             ),
-        ],
-        now,
-    );
-
-    let term = Arc::new(atomic::AtomicBool::new(false));
-    for sig in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
-        signal_hook::flag::register(sig, Arc::clone(&term)).unwrap();
+        );
     }
-    while !term.load(atomic::Ordering::Relaxed) {
-        let now = real_sleep(sched.get_next_timestamp().unwrap(), args.update_interval);
+    let mut sched = schedule::Scheduler::build(tasks, now, db::local_offset);
+
+    let mut reload = || {
+        let Some(path) = &args.config_path else {
+            return Err("no config file given".to_owned());
+        };
+        let config = config::Config::load(path)?;
+        if config.mqtt.is_some() || config.metrics.is_some() || config.backup.is_some() {
+            warn!("mqtt, metrics and backup changes take effect after restart");
+        }
+        *ref_limits.borrow_mut() = config.limits;
+        *ref_hooks.borrow_mut() = config.hooks;
+        info!("config reloaded");
+        Ok(())
+    };
+
+    loop {
+        let mut fds = ref_tracker.borrow().fds();
+        fds.extend(exporter.iter().flat_map(metrics::Exporter::fds));
+        fds.extend(control.iter().flat_map(control::Control::fds));
+        let mut write_fds = exporter
+            .as_ref()
+            .map(metrics::Exporter::write_fds)
+            .unwrap_or_default();
+        write_fds.extend(control.iter().flat_map(control::Control::write_fds));
+        let signal = event_loop
+            .wait(sched.get_next_timestamp().unwrap(), &fds, &write_fds)
+            .expect("poll error");
+        if let Some(signal) = signal {
+            info!("received signal {signal}");
+            break;
+        }
+        let now = SystemTime::now();
+        ref_tracker.borrow_mut().reap(&mut ref_db.borrow_mut(), now);
         sched.run_pending(now);
+        if let Some(exporter) = &mut exporter {
+            exporter.serve(&ref_db.borrow(), &sched);
+        }
+        if let Some(control) = &mut control {
+            let mut ctx = control::Context {
+                db: &ref_db,
+                sched: &sched,
+                reload: &mut reload,
+            };
+            control.process(|request| control::execute(request, &mut ctx));
+        }
     }
     info!("exiting");
 
@@ -94,4 +375,330 @@ fn main() {
         Ok(_) => info!("database flushed successfully"),
         Err(err) => error!("database flush error: {err}"),
     };
+    if let Some(ref_client) = ref_mqtt {
+        ref_client.borrow_mut().shutdown();
+    }
+}
+
+/// Control socket given on the command line or the default one.
+fn socket_path(args: &Args) -> Option<PathBuf> {
+    args.socket_path.clone().or_else(control::default_path)
+}
+
+/// Sends `request` to the running daemon and returns its result, or `None`
+/// if no daemon is listening. Exits if the request fails.
+fn request_daemon(args: &Args, request: &control::Request) -> Option<serde_json::Value> {
+    let path = socket_path(args)?;
+    match control::send(&path, request) {
+        Ok(response) if response["ok"] == true => Some(response["result"].clone()),
+        Ok(response) => {
+            error!("{}", response["error"].as_str().unwrap_or("request failed"));
+            std::process::exit(1);
+        }
+        Err(err)
+            if err.kind() == io::ErrorKind::NotFound
+                || err.kind() == io::ErrorKind::ConnectionRefused =>
+        {
+            None
+        }
+        Err(err) => {
+            error!("control socket error: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Lets the running daemon apply the exclusion so its cached playtime stays
+/// consistent, or edits the database directly if it is not running.
+fn exclude(args: &Args, exclusion: &sessions::Exclusion) {
+    let request = control::Request::Exclude(exclusion.clone());
+    let removed = match request_daemon(args, &request) {
+        Some(result) => result["removed_secs"].as_u64().unwrap_or(0),
+        None => {
+            let _lock = lock_db(args, "excluding playtime");
+            let mut conn = db::DeckDB::open(&args.db_path).expect("open db error");
+            let tx = conn.transaction().expect("exclude error");
+            let removed = sessions::exclude(&tx, exclusion.from, exclusion.to, exclusion.app)
+                .expect("exclude error");
+            tx.commit().expect("exclude error");
+            removed
+        }
+    };
+    println!("removed {removed}s");
+}
+
+/// Takes the database lock for a command that must not run alongside the
+/// daemon, exits if the daemon holds it.
+fn lock_db(args: &Args, action: &str) -> Option<lock::DbLock> {
+    match lock::DbLock::acquire(&args.db_path) {
+        Ok(lock) => lock,
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+            error!("the daemon is running, stop it before {action}");
+            std::process::exit(1);
+        }
+        Err(err) => {
+            error!("lock error: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn fsck(args: &Args, fix: bool) {
+    let _lock = fix.then(|| lock_db(args, "fixing the database"));
+
+    let mut conn = db::DeckDB::open(&args.db_path).expect("open db error");
+    let tx = conn.transaction().expect("fsck error");
+    let problems = fsck::check(&tx).expect("fsck error");
+    let mut unfixed = 0;
+    for problem in problems.iter() {
+        if fix && fsck::fix(&tx, problem).expect("fsck error") {
+            println!("fixed: {problem}");
+        } else {
+            println!("{problem}");
+            unfixed += 1;
+        }
+    }
+    tx.commit().expect("fsck error");
+
+    println!(
+        "{} problems found, {} fixed",
+        problems.len(),
+        problems.len() - unfixed
+    );
+    if unfixed > 0 {
+        std::process::exit(1);
+    }
+}
+
+fn restore(args: &Args, file: &Path, passphrase_file: Option<&Path>) {
+    let _lock = lock_db(args, "restoring the database");
+
+    let path = Path::new(&args.db_path);
+    let restored = match bundle::is_bundle(file) {
+        Ok(true) => bundle::read_passphrase(passphrase_file, false)
+            .and_then(|passphrase| backup::restore_bundle(file, &passphrase, path)),
+        Ok(false) => backup::restore(file, path),
+        Err(err) => Err(format!("{file:?}: {err}")),
+    };
+    match restored {
+        Ok(saved) => {
+            println!("restored {file:?}");
+            if let Some(saved) = saved {
+                println!("previous database saved to {saved:?}");
+            }
+        }
+        Err(err) => {
+            error!("restore error: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Lets the running daemon change the manually logged sessions, or runs
+/// `edit` directly in a transaction if it is not running. `edit` gets the
+/// active user and returns the result of `request`. Exits on errors.
+fn edit_sessions(
+    args: &Args,
+    request: control::Request,
+    edit: impl FnOnce(&rusqlite::Transaction, db::UserId) -> Result<serde_json::Value, String>,
+) -> serde_json::Value {
+    if let Some(result) = request_daemon(args, &request) {
+        return result;
+    }
+    let _lock = lock_db(args, "editing sessions");
+    let mut conn = db::DeckDB::open(&args.db_path).expect("open db error");
+    let tx = conn.transaction().expect("open db error");
+    let user_id = match steam::SteamHome::from_env().and_then(|home| home.active_user()) {
+        Some(user) => db::DeckDB::get_user_id(&tx, &user).expect("user error"),
+        None => db::UNKNOWN_USER_ID,
+    };
+    match edit(&tx, user_id) {
+        Ok(result) => {
+            tx.commit().expect("commit error");
+            result
+        }
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time ne tuda")
+        .as_secs()
+}
+
+fn print_scheduler_stats(stats: &[schedule::TaskStats]) {
+    let ms = |us: u64| format!("{:.1}ms", us as f64 / 1000.0);
+    println!(
+        "{:<16}  {:>8}  {:>6}  {:>9}  {:>9}  {:>9}  {:>9}  {:>9}  next run",
+        "task", "runs", "missed", "last", "average", "max", "drift", "max drift"
+    );
+    for task in stats {
+        println!(
+            "{:<16}  {:>8}  {:>6}  {:>9}  {:>9}  {:>9}  {:>9}  {:>9}  {}",
+            task.name,
+            task.runs,
+            task.missed_ticks,
+            ms(task.last_us),
+            ms(task.average_us),
+            ms(task.max_us),
+            ms(task.last_drift_us),
+            ms(task.max_drift_us),
+            sessions::format_timestamp(task.next_run)
+        );
+    }
+}
+
+fn main() {
+    env_logger::builder().format_timestamp(None).init();
+
+    let args = Args::parse();
+
+    match &args.command {
+        None => run(&args),
+        Some(Command::Report { user, daily }) => {
+            let conn = db::DeckDB::open(&args.db_path).expect("open db error");
+            if *daily {
+                let totals = report::daily_totals(&conn, user.as_deref(), db::local_offset)
+                    .expect("report error");
+                report::print_daily_totals(&totals);
+            } else {
+                let totals = report::app_totals(&conn, user.as_deref()).expect("report error");
+                report::print_app_totals(&totals);
+            }
+        }
+        Some(Command::Stats {
+            forgotten_days,
+            json,
+        }) => {
+            let conn = db::DeckDB::open(&args.db_path).expect("open db error");
+            let now = unix_now();
+            let stats =
+                stats::compute(&conn, now, *forgotten_days, db::local_offset).expect("stats error");
+            if *json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&stats).expect("stats error")
+                );
+            } else {
+                stats::print_stats(&stats);
+            }
+        }
+        Some(Command::Tui { refresh }) => {
+            let conn = db::DeckDB::open(&args.db_path).expect("open db error");
+            let socket = socket_path(&args);
+            tui::run(&conn, socket.as_deref(), *refresh).expect("tui error");
+        }
+        Some(Command::HtmlReport { out }) => {
+            let conn = db::DeckDB::open(&args.db_path).expect("open db error");
+            let now = unix_now();
+            let html = html::render(&conn, now, db::local_offset).expect("html report error");
+            std::fs::write(out, html).expect("write html report error");
+        }
+        Some(Command::ExportIcs { out }) => {
+            let conn = db::DeckDB::open(&args.db_path).expect("open db error");
+            let now = unix_now();
+            let ics = ics::export(&conn, now).expect("ics export error");
+            match out {
+                Some(out) => std::fs::write(out, ics).expect("write ics error"),
+                None => print!("{ics}"),
+            }
+        }
+        Some(Command::ExportBundle {
+            out,
+            passphrase_file,
+        }) => {
+            let passphrase = bundle::read_passphrase(passphrase_file.as_deref(), true)
+                .unwrap_or_else(|err| {
+                    error!("{err}");
+                    std::process::exit(1);
+                });
+            let conn = db::DeckDB::open(&args.db_path).expect("open db error");
+            let data = backup::snapshot(&conn).expect("export bundle error");
+            let bundle = bundle::seal(&data, &passphrase).expect("export bundle error");
+            bundle::write(out, &bundle).expect("write bundle error");
+        }
+        Some(Command::AddSession(session)) => {
+            let request = control::Request::AddSession(session.clone());
+            let result = edit_sessions(&args, request, |tx, user_id| {
+                let id = session.add(tx, user_id, unix_now())?;
+                Ok(serde_json::json!({ "id": id }))
+            });
+            println!("added session #{}", result["id"]);
+        }
+        Some(Command::EditSession(change)) => {
+            let request = control::Request::EditSession(change.clone());
+            edit_sessions(&args, request, |tx, _| {
+                change.apply(tx, unix_now())?;
+                Ok(serde_json::Value::Null)
+            });
+            println!("changed session #{}", change.id);
+        }
+        Some(Command::DeleteSession { id }) => {
+            let request = control::Request::DeleteSession { id: *id };
+            edit_sessions(&args, request, |tx, _| {
+                sessions::delete_session(tx, *id)?;
+                Ok(serde_json::Value::Null)
+            });
+            println!("deleted session #{id}");
+        }
+        Some(Command::Sessions) => {
+            let conn = db::DeckDB::open(&args.db_path).expect("open db error");
+            let list = sessions::list_sessions(&conn).expect("sessions error");
+            println!(
+                "{:>6}  {:>10}  {:<24}  {:<19}  {:<19}",
+                "id", "app_id", "alias", "start", "end"
+            );
+            for session in list {
+                println!(
+                    "{:>6}  {:>10}  {:<24}  {:<19}  {:<19}",
+                    session.id,
+                    session.app_id,
+                    session.alias.as_deref().unwrap_or("-"),
+                    sessions::format_timestamp(session.start),
+                    sessions::format_timestamp(session.end)
+                );
+            }
+        }
+        Some(Command::Ctl { request }) => {
+            let path = socket_path(&args).expect("XDG_RUNTIME_DIR is not set");
+            let response = control::send(&path, request).expect("control socket error");
+            println!("{response:#}");
+            if response["ok"] != true {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Debug {
+            target: DebugTarget::Scheduler,
+        }) => {
+            let path = socket_path(&args).expect("XDG_RUNTIME_DIR is not set");
+            let response = control::send(&path, &control::Request::SchedulerStats)
+                .expect("control socket error");
+            if response["ok"] != true {
+                error!(
+                    "{}",
+                    response["error"]
+                        .as_str()
+                        .unwrap_or("scheduler stats failed")
+                );
+                std::process::exit(1);
+            }
+            let stats: Vec<schedule::TaskStats> =
+                serde_json::from_value(response["result"].clone()).expect("scheduler stats error");
+            print_scheduler_stats(&stats);
+        }
+        Some(Command::Fsck { fix }) => fsck(&args, *fix),
+        Some(Command::Restore {
+            file,
+            passphrase_file,
+        }) => restore(&args, file, passphrase_file.as_deref()),
+        Some(Command::Exclude(exclusion)) => {
+            exclusion.validate().expect("exclude error");
+            exclude(&args, exclusion);
+        }
+    }
 }
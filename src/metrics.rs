@@ -0,0 +1,364 @@
+use crate::{db, schedule};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::{
+    collections::BTreeSet,
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::fd::{AsRawFd, RawFd},
+    time::{Duration, Instant},
+};
+
+/// Clients that take longer to send their request are dropped.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST: usize = 8192;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address to serve `/metrics` on, e.g. `0.0.0.0:9812`.
+    pub listen: String,
+}
+
+/// Values exported on every scrape.
+pub struct Snapshot {
+    pub playtimes: Vec<(db::AppId, Option<String>, u64)>,
+    pub running: Vec<db::AppId>,
+    pub suspends: u64,
+    pub commits: db::CommitStats,
+    pub missed_ticks: u64,
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+pub fn render(snapshot: &Snapshot) -> String {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "decktime_app_playtime_seconds",
+        "gauge",
+        "Total playtime per app, decreases when playtime is excluded or deleted.",
+    );
+    for (app_id, alias, value) in snapshot.playtimes.iter() {
+        let alias = escape(alias.as_deref().unwrap_or(""));
+        let _ = writeln!(
+            out,
+            "decktime_app_playtime_seconds{{app_id=\"{app_id}\",alias=\"{alias}\"}} {value}"
+        );
+    }
+
+    header(
+        &mut out,
+        "decktime_app_running",
+        "gauge",
+        "Whether the app is running.",
+    );
+    let known: BTreeSet<db::AppId> = snapshot
+        .playtimes
+        .iter()
+        .map(|&(app_id, _, _)| app_id)
+        .chain(snapshot.running.iter().copied())
+        .collect();
+    for app_id in known {
+        let running = snapshot.running.contains(&app_id) as u8;
+        let _ = writeln!(out, "decktime_app_running{{app_id=\"{app_id}\"}} {running}");
+    }
+
+    header(
+        &mut out,
+        "decktime_suspends_total",
+        "counter",
+        "Number of suspends.",
+    );
+    let _ = writeln!(out, "decktime_suspends_total {}", snapshot.suspends);
+
+    header(
+        &mut out,
+        "decktime_commit_duration_seconds",
+        "summary",
+        "Time spent committing to the database.",
+    );
+    let _ = writeln!(
+        out,
+        "decktime_commit_duration_seconds_sum {}",
+        snapshot.commits.total.as_secs_f64()
+    );
+    let _ = writeln!(
+        out,
+        "decktime_commit_duration_seconds_count {}",
+        snapshot.commits.count
+    );
+    header(
+        &mut out,
+        "decktime_last_commit_duration_seconds",
+        "gauge",
+        "Duration of the last commit.",
+    );
+    let _ = writeln!(
+        out,
+        "decktime_last_commit_duration_seconds {}",
+        snapshot.commits.last.as_secs_f64()
+    );
+
+    header(
+        &mut out,
+        "decktime_scheduler_missed_ticks_total",
+        "counter",
+        "Scheduler ticks skipped because callbacks ran late.",
+    );
+    let _ = writeln!(
+        out,
+        "decktime_scheduler_missed_ticks_total {}",
+        snapshot.missed_ticks
+    );
+
+    out
+}
+
+fn respond(request: &[u8], db: &db::DeckDB, sched: &schedule::Scheduler) -> io::Result<Vec<u8>> {
+    let request = String::from_utf8_lossy(request);
+    let path = request.split_ascii_whitespace().nth(1).unwrap_or("");
+    let (status, body) = match (request.starts_with("GET "), path) {
+        (true, "/metrics") => {
+            let snapshot = Snapshot {
+                playtimes: db.app_playtimes().map_err(io::Error::other)?,
+                running: db.running_apps().into_iter().map(|(id, _)| id).collect(),
+                suspends: db
+                    .count_events(db::EventType::Suspended)
+                    .map_err(io::Error::other)?,
+                commits: db.commit_stats(),
+                missed_ticks: sched.missed_ticks(),
+            };
+            ("200 OK", render(&snapshot))
+        }
+        _ => ("404 Not Found", "not found\n".to_owned()),
+    };
+
+    Ok(format!(
+        "HTTP/1.1 {status}\r\n\
+        Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .into_bytes())
+}
+
+struct Client {
+    stream: TcpStream,
+    accepted: Instant,
+    buf: Vec<u8>,
+    /// Response not written yet, `None` until the request is complete.
+    out: Option<Vec<u8>>,
+}
+
+impl Client {
+    /// Reads and answers what is available, returns `false` once the client
+    /// is done or gone.
+    fn process(&mut self, db: &db::DeckDB, sched: &schedule::Scheduler) -> bool {
+        if self.out.is_none() {
+            let mut chunk = [0u8; 1024];
+            let complete = loop {
+                match self.stream.read(&mut chunk) {
+                    Ok(0) => break true,
+                    Ok(len) => self.buf.extend(&chunk[..len]),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break false,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        debug!("metrics request error: {err}");
+                        return false;
+                    }
+                }
+            };
+            if !complete
+                && self.buf.len() < MAX_REQUEST
+                && !self.buf.windows(4).any(|w| w == b"\r\n\r\n")
+            {
+                return self.accepted.elapsed() < CLIENT_TIMEOUT;
+            }
+            match respond(&self.buf, db, sched) {
+                Ok(out) => self.out = Some(out),
+                Err(err) => {
+                    debug!("metrics request error: {err}");
+                    return false;
+                }
+            }
+        }
+
+        let Some(out) = self.out.as_mut() else {
+            return false;
+        };
+        while !out.is_empty() {
+            match self.stream.write(out) {
+                Ok(0) => return false,
+                Ok(len) => {
+                    out.drain(..len);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return self.accepted.elapsed() < CLIENT_TIMEOUT;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    debug!("metrics request error: {err}");
+                    return false;
+                }
+            }
+        }
+        false
+    }
+}
+
+/// HTTP endpoint served from the main loop without extra threads.
+pub struct Exporter {
+    listener: TcpListener,
+    clients: Vec<Client>,
+}
+
+impl Exporter {
+    pub fn bind(config: &MetricsConfig) -> io::Result<Exporter> {
+        let listener = TcpListener::bind(&config.listen)?;
+        listener.set_nonblocking(true)?;
+        info!("serving metrics on {}", listener.local_addr()?);
+        Ok(Exporter {
+            listener,
+            clients: Vec::new(),
+        })
+    }
+
+    /// File descriptors to wait on for readability.
+    pub fn fds(&self) -> Vec<RawFd> {
+        let mut fds = vec![self.listener.as_raw_fd()];
+        fds.extend(
+            self.clients
+                .iter()
+                .filter(|client| client.out.is_none())
+                .map(|client| client.stream.as_raw_fd()),
+        );
+        fds
+    }
+
+    /// File descriptors of clients waiting for the rest of their response.
+    pub fn write_fds(&self) -> Vec<RawFd> {
+        self.clients
+            .iter()
+            .filter(|client| client.out.is_some())
+            .map(|client| client.stream.as_raw_fd())
+            .collect()
+    }
+
+    /// Accepts new clients and answers all complete requests.
+    pub fn serve(&mut self, db: &db::DeckDB, sched: &schedule::Scheduler) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => match stream.set_nonblocking(true) {
+                    Ok(()) => self.clients.push(Client {
+                        stream,
+                        accepted: Instant::now(),
+                        buf: Vec::new(),
+                        out: None,
+                    }),
+                    Err(err) => warn!("metrics accept error: {err}"),
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    warn!("metrics accept error: {err}");
+                    break;
+                }
+            }
+        }
+        self.clients.retain_mut(|client| client.process(db, sched));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        thread,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    #[test]
+    fn render_snapshot() {
+        let text = render(&Snapshot {
+            playtimes: vec![(570, Some("Dota \"2\"".to_owned()), 3600), (10, None, 5)],
+            running: vec![570, 20],
+            suspends: 3,
+            commits: db::CommitStats {
+                count: 2,
+                total: Duration::from_millis(1500),
+                last: Duration::from_millis(500),
+            },
+            missed_ticks: 4,
+        });
+
+        for line in [
+            "# TYPE decktime_app_playtime_seconds gauge",
+            "decktime_app_playtime_seconds{app_id=\"570\",alias=\"Dota \\\"2\\\"\"} 3600",
+            "decktime_app_playtime_seconds{app_id=\"10\",alias=\"\"} 5",
+            "decktime_app_running{app_id=\"570\"} 1",
+            "decktime_app_running{app_id=\"10\"} 0",
+            "decktime_app_running{app_id=\"20\"} 1",
+            "decktime_suspends_total 3",
+            "decktime_commit_duration_seconds_sum 1.5",
+            "decktime_commit_duration_seconds_count 2",
+            "decktime_last_commit_duration_seconds 0.5",
+            "decktime_scheduler_missed_ticks_total 4",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {line:?}");
+        }
+    }
+
+    #[test]
+    fn partial_request() {
+        let config = MetricsConfig {
+            listen: "127.0.0.1:0".to_owned(),
+        };
+        let mut exporter = Exporter::bind(&config).unwrap();
+        let addr = exporter.listener.local_addr().unwrap();
+        let start = UNIX_EPOCH + Duration::from_secs(3600);
+        let mut db = db::DeckDB::build(":memory:", start, None).unwrap();
+        db.event(start, Some(570), db::EventType::Started).unwrap();
+        let sched = schedule::Scheduler::build(Vec::new(), SystemTime::now(), db::local_offset);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n").unwrap();
+        for _ in 0..100 {
+            exporter.serve(&db, &sched);
+            if !exporter.clients.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Nothing is answered before the request is complete.
+        exporter.serve(&db, &sched);
+        assert_eq!(exporter.clients.len(), 1);
+        assert!(exporter.write_fds().is_empty());
+
+        stream.write_all(b"Host: deck\r\n\r\n").unwrap();
+        for _ in 0..100 {
+            exporter.serve(&db, &sched);
+            if exporter.clients.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\ndecktime_app_running{app_id=\"570\"} 1\n"));
+        db.flush(start).unwrap();
+    }
+}
@@ -0,0 +1,595 @@
+use crate::{config, db};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    fs,
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    rc::Rc,
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+const MAX_QUEUED: usize = 256;
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|s| s.trim().to_owned())
+        .unwrap_or_else(|_| "steamdeck".to_owned())
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_owned()
+}
+
+fn default_keep_alive() -> Duration {
+    Duration::from_secs(60)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Defaults to `decktime-<hostname>`.
+    pub client_id: Option<String>,
+    /// Base topic, defaults to `decktime/<hostname>`.
+    pub topic: Option<String>,
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    #[serde(default = "default_keep_alive")]
+    #[serde(deserialize_with = "config::deserialize_duration")]
+    pub keep_alive: Duration,
+}
+
+fn encode_len(mut len: usize, buf: &mut Vec<u8>) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        buf.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn encode_str(s: &[u8], buf: &mut Vec<u8>) {
+    buf.extend((s.len() as u16).to_be_bytes());
+    buf.extend(s);
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut buf = vec![header];
+    encode_len(body.len(), &mut buf);
+    buf.extend(body);
+    buf
+}
+
+fn connect_packet(config: &MqttConfig, client_id: &str, will_topic: &str) -> Vec<u8> {
+    let mut flags = 0x02 | 0x04 | 0x20;
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+
+    let mut body = Vec::new();
+    encode_str(b"MQTT", &mut body);
+    body.push(4);
+    body.push(flags);
+    body.extend((config.keep_alive.as_secs() as u16).to_be_bytes());
+    encode_str(client_id.as_bytes(), &mut body);
+    encode_str(will_topic.as_bytes(), &mut body);
+    encode_str(b"offline", &mut body);
+    if let Some(username) = &config.username {
+        encode_str(username.as_bytes(), &mut body);
+    }
+    if let Some(password) = &config.password {
+        encode_str(password.as_bytes(), &mut body);
+    }
+    packet(0x10, &body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    encode_str(topic.as_bytes(), &mut body);
+    body.extend(payload);
+    packet(0x30 | retain as u8, &body)
+}
+
+/// Resolves the broker address, connects and waits for the CONNACK. Runs on
+/// its own thread since all of it may block.
+fn connect(host: &str, port: u16, connect_packet: &[u8]) -> io::Result<TcpStream> {
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+
+    stream.write_all(connect_packet)?;
+    let mut connack = [0u8; 4];
+    stream.read_exact(&mut connack)?;
+    if connack[0] != 0x20 || connack[3] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("connection refused with code {}", connack[3]),
+        ));
+    }
+    stream.set_nonblocking(true)?;
+    Ok(stream)
+}
+
+/// Minimal MQTT 3.1.1 publisher using QoS 0, which keeps messages queued
+/// while the broker is unreachable. The main loop never blocks on the
+/// broker: connecting happens on a separate thread and writes are buffered.
+pub struct MqttClient {
+    config: MqttConfig,
+    client_id: String,
+    topic: String,
+    node_id: String,
+    stream: Option<TcpStream>,
+    connecting: Option<Receiver<io::Result<TcpStream>>>,
+    /// Bytes not written to the current connection yet.
+    out: Vec<u8>,
+    queue: VecDeque<Vec<u8>>,
+    last_attempt: Option<Instant>,
+    last_write: Instant,
+}
+
+impl MqttClient {
+    pub fn build(config: MqttConfig) -> MqttClient {
+        let host = hostname();
+        let node_id: String = host
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        MqttClient {
+            client_id: config
+                .client_id
+                .clone()
+                .unwrap_or_else(|| format!("decktime-{host}")),
+            topic: config
+                .topic
+                .clone()
+                .unwrap_or_else(|| format!("decktime/{node_id}")),
+            node_id,
+            config,
+            stream: None,
+            connecting: None,
+            out: Vec::new(),
+            queue: VecDeque::new(),
+            last_attempt: None,
+            last_write: Instant::now(),
+        }
+    }
+
+    fn availability_topic(&self) -> String {
+        format!("{}/availability", self.topic)
+    }
+
+    fn state_topic(&self) -> String {
+        format!("{}/state", self.topic)
+    }
+
+    fn discovery(&self) -> Vec<Vec<u8>> {
+        let device = json!({
+            "identifiers": [format!("decktime_{}", self.node_id)],
+            "name": self.node_id,
+            "model": "decktime",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        let entity = |component: &str, object_id: &str, mut payload: serde_json::Value| {
+            let unique_id = format!("decktime_{}_{object_id}", self.node_id);
+            payload["unique_id"] = json!(unique_id);
+            payload["object_id"] = json!(unique_id);
+            payload["state_topic"] = json!(self.state_topic());
+            payload["availability_topic"] = json!(self.availability_topic());
+            payload["device"] = device.clone();
+            publish_packet(
+                &format!(
+                    "{}/{component}/{}/{object_id}/config",
+                    self.config.discovery_prefix, self.node_id
+                ),
+                payload.to_string().as_bytes(),
+                true,
+            )
+        };
+        vec![
+            entity(
+                "sensor",
+                "playing",
+                json!({
+                    "name": "Playing",
+                    "icon": "mdi:gamepad-variant",
+                    "value_template": "{{ value_json.playing | default('None', true) }}",
+                    "json_attributes_topic": self.state_topic(),
+                }),
+            ),
+            entity(
+                "sensor",
+                "session",
+                json!({
+                    "name": "Session",
+                    "device_class": "duration",
+                    "unit_of_measurement": "s",
+                    "value_template": "{{ value_json.session_secs }}",
+                }),
+            ),
+            entity(
+                "binary_sensor",
+                "suspended",
+                json!({
+                    "name": "Suspended",
+                    "value_template": "{{ 'ON' if value_json.suspended else 'OFF' }}",
+                }),
+            ),
+        ]
+    }
+
+    fn start_connect(&mut self) {
+        self.last_attempt = Some(Instant::now());
+        let (host, port) = (self.config.host.clone(), self.config.port);
+        let packet = connect_packet(&self.config, &self.client_id, &self.availability_topic());
+        let (sender, receiver) = mpsc::channel();
+        let spawned = thread::Builder::new()
+            .name("mqtt-connect".to_owned())
+            .spawn(move || {
+                let _ = sender.send(connect(&host, port, &packet));
+            });
+        match spawned {
+            Ok(_) => self.connecting = Some(receiver),
+            Err(err) => warn!("unable to connect to mqtt broker: {err}"),
+        }
+    }
+
+    /// Takes over the stream of a finished connection attempt.
+    fn connected(&mut self, result: io::Result<TcpStream>) {
+        match result {
+            Ok(stream) => {
+                info!(
+                    "connected to mqtt broker {}:{}",
+                    self.config.host, self.config.port
+                );
+                self.out = self.discovery().concat();
+                self.out
+                    .extend(publish_packet(&self.availability_topic(), b"online", true));
+                self.stream = Some(stream);
+                self.last_write = Instant::now();
+            }
+            Err(err) => warn!("unable to connect to mqtt broker: {err}"),
+        }
+    }
+
+    fn disconnect(&mut self, err: io::Error) {
+        warn!("mqtt connection lost: {err}");
+        self.stream = None;
+        self.out.clear();
+    }
+
+    /// Writes queued messages as far as the socket accepts them, connecting
+    /// to the broker if needed.
+    pub fn flush(&mut self) {
+        if let Some(connecting) = &self.connecting {
+            match connecting.try_recv() {
+                Ok(result) => {
+                    self.connecting = None;
+                    self.connected(result);
+                }
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => self.connecting = None,
+            }
+        }
+        let Some(stream) = self.stream.as_mut() else {
+            if self
+                .last_attempt
+                .is_none_or(|last| last.elapsed() >= RECONNECT_DELAY)
+            {
+                self.start_connect();
+            }
+            return;
+        };
+
+        loop {
+            if self.out.is_empty() {
+                match self.queue.pop_front() {
+                    Some(packet) => self.out = packet,
+                    None => return,
+                }
+            }
+            match stream.write(&self.out) {
+                Ok(0) => return self.disconnect(io::ErrorKind::WriteZero.into()),
+                Ok(len) => {
+                    self.out.drain(..len);
+                    self.last_write = Instant::now();
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return self.disconnect(err),
+            }
+        }
+    }
+
+    pub fn publish(&mut self, topic: &str, payload: &[u8]) {
+        if self.queue.len() >= MAX_QUEUED {
+            debug!("mqtt queue is full, dropping the oldest message");
+            self.queue.pop_front();
+        }
+        self.queue.push_back(publish_packet(topic, payload, true));
+        self.flush();
+    }
+
+    /// Keeps the connection alive and notices when the broker goes away.
+    pub fn tick(&mut self) {
+        if let Some(stream) = self.stream.as_mut() {
+            let mut buf = [0u8; 256];
+            loop {
+                match stream.read(&mut buf) {
+                    Ok(0) => {
+                        warn!("mqtt broker closed the connection");
+                        self.stream = None;
+                        self.out.clear();
+                        break;
+                    }
+                    Ok(_) => continue,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) => {
+                        self.disconnect(err);
+                        break;
+                    }
+                }
+            }
+        }
+
+        if self.stream.is_some()
+            && self.out.is_empty()
+            && self.queue.is_empty()
+            && self.last_write.elapsed() >= self.config.keep_alive / 2
+        {
+            self.out = vec![0xc0, 0x00];
+        }
+        self.flush();
+    }
+
+    /// Sends what is queued, marks the device offline and disconnects
+    /// gracefully. Blocks briefly, which is fine once the main loop is done.
+    pub fn shutdown(&mut self) {
+        if let Some(connecting) = self.connecting.take() {
+            if let Ok(result) = connecting.recv_timeout(CONNECT_TIMEOUT * 2) {
+                self.connected(result);
+            }
+        }
+        let Some(mut stream) = self.stream.take() else {
+            return;
+        };
+        self.queue
+            .push_back(publish_packet(&self.availability_topic(), b"offline", true));
+        self.queue.push_back(vec![0xe0, 0x00]);
+        let result = stream.set_nonblocking(false).and_then(|_| {
+            stream.write_all(&self.out)?;
+            self.queue
+                .iter()
+                .try_for_each(|packet| stream.write_all(packet))
+        });
+        if let Err(err) = result {
+            debug!("mqtt shutdown error: {err}");
+        }
+    }
+}
+
+struct Session {
+    alias: Option<String>,
+    started: SystemTime,
+}
+
+fn unix_ts(timestamp: SystemTime) -> u64 {
+    timestamp
+        .duration_since(UNIX_EPOCH)
+        .map(|ts| ts.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn get_mqtt_listener(ref_client: Rc<RefCell<MqttClient>>) -> db::Listener {
+    let mut sessions = BTreeMap::<db::AppId, Session>::new();
+    let mut suspended = false;
+
+    Box::new(move |event| {
+        match (event.event_type, event.app_id) {
+            (db::EventType::Started, Some(app_id)) => {
+                sessions.insert(
+                    app_id,
+                    Session {
+                        alias: event.alias.clone(),
+                        started: event.timestamp,
+                    },
+                );
+            }
+            (db::EventType::Stopped, Some(app_id)) => {
+                sessions.remove(&app_id);
+            }
+            (db::EventType::Suspended, _) => suspended = true,
+            (db::EventType::Resumed, _) => suspended = false,
+            _ => {}
+        }
+
+        let elapsed = |session: &Session| {
+            event
+                .timestamp
+                .duration_since(session.started)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0)
+        };
+        let running: Vec<_> = sessions
+            .iter()
+            .map(|(app_id, session)| {
+                json!({
+                    "app_id": app_id,
+                    "alias": session.alias,
+                    "started": unix_ts(session.started),
+                    "elapsed_secs": elapsed(session),
+                })
+            })
+            .collect();
+        let current = sessions.iter().max_by_key(|(_, session)| session.started);
+        let state = json!({
+            "playing": current.map(|(app_id, session)| {
+                session.alias.clone().unwrap_or_else(|| app_id.to_string())
+            }),
+            "session_secs": current.map(|(_, session)| elapsed(session)).unwrap_or(0),
+            "suspended": suspended,
+            "running": running,
+            "timestamp": unix_ts(event.timestamp),
+        });
+
+        let mut client = ref_client.borrow_mut();
+        let topic = client.state_topic();
+        client.publish(&topic, state.to_string().as_bytes());
+    })
+}
+
+pub fn get_mqtt_func(ref_client: Rc<RefCell<MqttClient>>) -> impl FnMut(SystemTime) {
+    move |_| ref_client.borrow_mut().tick()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut header = [0u8; 1];
+        stream.read_exact(&mut header).unwrap();
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).unwrap();
+            len |= ((byte[0] & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).unwrap();
+        (header[0], body)
+    }
+
+    fn topic(body: &[u8]) -> String {
+        let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+        String::from_utf8(body[2..2 + len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn remaining_length() {
+        for (len, encoded) in [
+            (0, vec![0x00]),
+            (127, vec![0x7f]),
+            (128, vec![0x80, 0x01]),
+            (16_383, vec![0xff, 0x7f]),
+            (2_097_152, vec![0x80, 0x80, 0x80, 0x01]),
+        ] {
+            let mut buf = Vec::new();
+            encode_len(len, &mut buf);
+            assert_eq!(buf, encoded);
+        }
+    }
+
+    #[test]
+    fn publish_with_buffering() {
+        let broker = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = broker.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            // The first attempt is refused with "not authorized".
+            let (mut stream, _) = broker.accept().unwrap();
+            assert_eq!(read_packet(&mut stream).0, 0x10);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x05]).unwrap();
+            drop(stream);
+
+            let (mut stream, _) = broker.accept().unwrap();
+            let (header, body) = read_packet(&mut stream);
+            assert_eq!(header, 0x10);
+            assert_eq!(body[7], 0x02 | 0x04 | 0x20 | 0x80);
+            stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+
+            let mut topics = Vec::new();
+            let mut payloads = Vec::new();
+            loop {
+                let (header, body) = read_packet(&mut stream);
+                if header == 0xe0 {
+                    break;
+                }
+                assert_eq!(header, 0x31);
+                let topic = topic(&body);
+                payloads.push(String::from_utf8(body[2 + topic.len()..].to_vec()).unwrap());
+                topics.push(topic);
+            }
+            (topics, payloads)
+        });
+
+        let config: config::Config = toml::from_str(&format!(
+            "[mqtt]\nhost = \"127.0.0.1\"\nport = {port}\ntopic = \"deck\"\nusername = \"user\"\n"
+        ))
+        .unwrap();
+        let client = Rc::new(RefCell::new(MqttClient::build(config.mqtt.unwrap())));
+        let mut listener = get_mqtt_listener(Rc::clone(&client));
+
+        let event = |secs, app_id, event_type| db::Event {
+            timestamp: UNIX_EPOCH + Duration::from_secs(secs),
+            app_id,
+            alias: Some("Game".to_owned()),
+            event_type,
+            session: None,
+        };
+        listener(&event(100, Some(7), db::EventType::Started));
+        while client.borrow().connecting.is_some() {
+            client.borrow_mut().tick();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(client.borrow().stream.is_none());
+        assert_eq!(client.borrow().queue.len(), 1);
+
+        client.borrow_mut().last_attempt = None;
+        listener(&event(160, None, db::EventType::Suspended));
+        client.borrow_mut().shutdown();
+
+        let (topics, payloads) = handle.join().unwrap();
+        assert_eq!(topics.len(), 7);
+        assert!(topics[0].starts_with("homeassistant/sensor/"));
+        assert_eq!(
+            topics[3..],
+            [
+                "deck/availability",
+                "deck/state",
+                "deck/state",
+                "deck/availability"
+            ]
+        );
+        assert_eq!(payloads[3], "online");
+        assert_eq!(payloads[6], "offline");
+
+        let state: serde_json::Value = serde_json::from_str(&payloads[5]).unwrap();
+        assert_eq!(state["playing"], "Game");
+        assert_eq!(state["session_secs"], 60);
+        assert_eq!(state["suspended"], true);
+        assert_eq!(state["running"][0]["app_id"], 7);
+    }
+}
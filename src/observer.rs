@@ -1,83 +1,380 @@
-use crate::db;
-use log::info;
+use crate::{db, procmon, steam};
+use log::{debug, info, warn};
 use std::{
     cell::RefCell,
-    collections::HashSet,
-    fs,
+    cmp,
+    collections::{HashMap, HashSet},
+    fs, io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::{Path, PathBuf},
     rc::Rc,
-    time::{Duration, SystemTime},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-fn find_pid_by_name(appname: &str) -> Option<u32> {
-    fs::read_dir("/proc")
-        .ok()?
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Monitor {
+    Netlink,
+    Poll,
+}
+
+/// Whether the process is the Steam client itself rather than the `steam`
+/// shell wrapper, `steam.sh` or `steamwebhelper`, which share its name.
+/// Flatpak installs run the same binary from inside the sandbox.
+fn is_steam_client(proc_dir: &Path) -> bool {
+    let Ok(exe) = fs::read_link(proc_dir.join("exe")) else {
+        return false;
+    };
+    let Some(exe) = exe.to_str() else {
+        return false;
+    };
+    // Steam replaces its binary when it updates itself.
+    let exe = Path::new(exe.strip_suffix(" (deleted)").unwrap_or(exe));
+    exe.file_name().is_some_and(|name| name == "steam")
+        && exe
+            .parent()
+            .and_then(Path::file_name)
+            .is_some_and(|dir| dir == "ubuntu12_32" || dir == "ubuntu12_64")
+}
+
+fn is_flatpak(proc_dir: &Path) -> bool {
+    fs::read_to_string(proc_dir.join("root/.flatpak-info"))
+        .is_ok_and(|info| info.contains("com.valvesoftware.Steam"))
+}
+
+fn find_steam_pids(proc_root: &Path) -> Vec<u32> {
+    let Ok(dir) = fs::read_dir(proc_root) else {
+        return Vec::new();
+    };
+    let mut pids: Vec<u32> = dir
         .filter_map(Result::ok)
-        .filter(|entry| {
-            fs::read_to_string(entry.path().join("comm"))
-                .is_ok_and(|s| s[..s.len() - 1] == *appname)
-        })
-        .filter_map(|entry| entry.path().file_name()?.to_str()?.parse().ok())
-        .next()
+        .filter(|entry| is_steam_client(&entry.path()))
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .collect();
+    pids.sort_unstable();
+    pids
 }
 
-fn get_app_id_by_pid(pid: u32) -> Option<u32> {
-    let cmdline = fs::read_to_string(format!("/proc/{pid}/cmdline")).ok()?;
+fn get_app_id(proc_dir: &Path) -> Option<u32> {
+    let cmdline = fs::read_to_string(proc_dir.join("cmdline")).ok()?;
     let pos = cmdline.find("AppId=")? + 6;
     let len = cmdline[pos..].find("\x00")?;
     cmdline[pos..pos + len].parse::<u32>().ok()
 }
 
-pub fn get_update_func(value: u64, ref_db: Rc<RefCell<db::DeckDB>>) -> impl FnMut(SystemTime) {
-    let mut ppid = None;
-    let mut apps = HashSet::<u32>::new();
+fn get_ppid(proc_dir: &Path) -> Option<u32> {
+    let stat = fs::read_to_string(proc_dir.join("stat")).ok()?;
+    stat[stat.rfind(')')? + 1..]
+        .split_ascii_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
 
-    return move |now| {
-        if ppid.is_none() {
-            ppid = find_pid_by_name("steam");
-            if ppid.is_none() {
-                return;
-            };
-            info!("steam pid={}", ppid.unwrap());
+fn get_children(proc_dir: &Path) -> Option<Vec<u32>> {
+    let dir = fs::read_dir(proc_dir.join("task")).ok()?;
+    Some(
+        dir.filter_map(|entry| fs::read_to_string(entry.ok()?.path().join("children")).ok())
+            .flat_map(|pids| {
+                pids.split_ascii_whitespace()
+                    .filter_map(|pid| pid.parse().ok())
+                    .collect::<Vec<_>>()
+            })
+            .collect(),
+    )
+}
+
+struct Game {
+    app_id: db::AppId,
+    pidfd: Option<OwnedFd>,
+}
+
+fn open_pidfd(pid: u32) -> Option<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 {
+        debug!(
+            "pidfd_open failed for pid={pid}: {}",
+            io::Error::last_os_error()
+        );
+        return None;
+    }
+    Some(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+pub struct Tracker {
+    connector: Option<procmon::ProcConnector>,
+    proc_root: PathBuf,
+    steam_pids: HashSet<u32>,
+    games: HashMap<u32, Game>,
+    last_ts: SystemTime,
+}
+
+impl Tracker {
+    pub fn build(monitor: Monitor, proc_root: &Path) -> Tracker {
+        let connector = match monitor {
+            Monitor::Netlink => match procmon::ProcConnector::open() {
+                Ok(connector) => {
+                    info!("using proc connector to monitor processes");
+                    Some(connector)
+                }
+                Err(err) => {
+                    warn!("proc connector is not available ({err}), falling back to polling");
+                    None
+                }
+            },
+            Monitor::Poll => None,
+        };
+        Tracker {
+            connector,
+            proc_root: proc_root.to_owned(),
+            steam_pids: HashSet::new(),
+            games: HashMap::new(),
+            last_ts: UNIX_EPOCH,
         }
+    }
 
-        let mut db = ref_db.borrow_mut();
+    /// File descriptors that become readable when a tracked game exits.
+    pub fn fds(&self) -> Vec<RawFd> {
+        self.games
+            .values()
+            .filter_map(|game| Some(game.pidfd.as_ref()?.as_raw_fd()))
+            .collect()
+    }
 
-        let Ok(dir) = fs::read_dir(format!("/proc/{}/task", ppid.unwrap())) else {
-            info!("steam pid not found");
-            apps.drain().for_each(|app_id| {
-                db.event(now, Some(app_id), db::EventType::Stopped)
-                    .expect("event error")
-            });
-            ppid = None;
+    /// Records games whose pidfd reports an exit as stopped at `now`.
+    pub fn reap(&mut self, db: &mut db::DeckDB, now: SystemTime) {
+        let (pids, mut pollfds): (Vec<u32>, Vec<libc::pollfd>) = self
+            .games
+            .iter()
+            .filter_map(|(&pid, game)| {
+                let fd = game.pidfd.as_ref()?.as_raw_fd();
+                let events = libc::POLLIN;
+                Some((
+                    pid,
+                    libc::pollfd {
+                        fd,
+                        events,
+                        revents: 0,
+                    },
+                ))
+            })
+            .unzip();
+        if unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, 0) } <= 0 {
+            return;
+        }
+        for (pid, pollfd) in pids.into_iter().zip(pollfds) {
+            if pollfd.revents != 0 {
+                debug!("pidfd reported exit of pid={pid}");
+                self.stop(db, now, pid);
+            }
+        }
+    }
+
+    fn proc_dir(&self, pid: u32) -> PathBuf {
+        self.proc_root.join(pid.to_string())
+    }
+
+    fn is_running(&self, app_id: db::AppId) -> bool {
+        self.games.values().any(|game| game.app_id == app_id)
+    }
+
+    pub fn running_apps(&self) -> HashSet<db::AppId> {
+        self.games.values().map(|game| game.app_id).collect()
+    }
+
+    /// Asks the games of `app_id`, or all games, to terminate.
+    pub fn kill(&self, app_id: Option<db::AppId>) {
+        for (&pid, game) in self.games.iter() {
+            if app_id.is_none_or(|app_id| app_id == game.app_id) {
+                info!("terminating app_id={} with pid={pid}", game.app_id);
+                unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+            }
+        }
+    }
+
+    fn emit(
+        &mut self,
+        db: &mut db::DeckDB,
+        now: SystemTime,
+        app_id: db::AppId,
+        event_type: db::EventType,
+    ) {
+        // Process events are read late, meanwhile other events may have been
+        // recorded with a later time.
+        self.last_ts = cmp::max(cmp::max(now, self.last_ts), db.last_timestamp());
+        db.event(self.last_ts, Some(app_id), event_type)
+            .expect("event error");
+    }
+
+    fn start(&mut self, db: &mut db::DeckDB, now: SystemTime, pid: u32, app_id: db::AppId) {
+        if !self.is_running(app_id) {
+            self.emit(db, now, app_id, db::EventType::Started);
+        }
+        let pidfd = open_pidfd(pid);
+        self.games.insert(pid, Game { app_id, pidfd });
+    }
+
+    fn stop(&mut self, db: &mut db::DeckDB, now: SystemTime, pid: u32) {
+        let Some(game) = self.games.remove(&pid) else {
             return;
         };
+        if !self.is_running(game.app_id) {
+            self.emit(db, now, game.app_id, db::EventType::Stopped);
+        }
+    }
 
-        let mut closed_apps = apps.clone();
+    /// Starts games found among the children of the Steam clients and
+    /// stops the tracked ones that exited. Games outlive a Steam restart.
+    fn rescan(&mut self, db: &mut db::DeckDB, now: SystemTime) {
+        let mut children = Vec::new();
+        self.steam_pids.retain(|&steam_pid| {
+            match get_children(&self.proc_root.join(steam_pid.to_string())) {
+                Some(pids) => {
+                    children.extend(pids);
+                    true
+                }
+                None => {
+                    info!("steam pid={steam_pid} not found");
+                    false
+                }
+            }
+        });
 
-        dir.filter_map(|entry| fs::read_to_string(entry.ok()?.path().join("children")).ok())
-            .flat_map(|pids| {
-                pids.split_ascii_whitespace()
-                    .filter_map(|pid| pid.parse().ok())
-                    .collect::<Vec<_>>()
-            })
-            .filter_map(get_app_id_by_pid)
-            .for_each(|app_id| {
-                if apps.insert(app_id) == true {
-                    db.event(now, Some(app_id), db::EventType::Started)
-                        .expect("event error");
-                } else if closed_apps.remove(&app_id) == true {
-                    db.update(app_id, value);
-                } else {
+        for pid in children {
+            if self.games.contains_key(&pid) {
+                continue;
+            }
+            if let Some(app_id) = get_app_id(&self.proc_dir(pid)) {
+                if self.is_running(app_id) {
                     info!("duplicated app_id={app_id}");
                 }
-            });
+                self.start(db, now, pid, app_id);
+            }
+        }
 
-        closed_apps.into_iter().for_each(|app_id| {
-            apps.remove(&app_id);
-            db.event(now, Some(app_id), db::EventType::Stopped)
-                .expect("event error");
-        });
-    };
+        let exited: Vec<u32> = self
+            .games
+            .iter()
+            .filter(|(&pid, game)| get_app_id(&self.proc_dir(pid)) != Some(game.app_id))
+            .map(|(&pid, _)| pid)
+            .collect();
+        exited.into_iter().for_each(|pid| self.stop(db, now, pid));
+    }
+
+    fn add_steam(&mut self, steam_pid: u32) {
+        if !self.steam_pids.insert(steam_pid) {
+            return;
+        }
+        let flatpak = if is_flatpak(&self.proc_dir(steam_pid)) {
+            " (flatpak)"
+        } else {
+            ""
+        };
+        info!("steam pid={steam_pid}{flatpak}");
+    }
+
+    /// Looks for new Steam clients, returns whether any was found. The proc
+    /// connector reports new clients, so with it this only runs while none is
+    /// known.
+    fn find_steam(&mut self) -> bool {
+        if self.connector.is_some() && !self.steam_pids.is_empty() {
+            return false;
+        }
+        let known = self.steam_pids.len();
+        find_steam_pids(&self.proc_root)
+            .into_iter()
+            .for_each(|steam_pid| self.add_steam(steam_pid));
+        self.steam_pids.len() > known
+    }
+
+    fn handle(&mut self, db: &mut db::DeckDB, now: SystemTime, event: procmon::ProcEvent) {
+        match event {
+            procmon::ProcEvent::Exec { pid, timestamp } => {
+                let proc_dir = self.proc_dir(pid);
+                if is_steam_client(&proc_dir) {
+                    self.add_steam(pid);
+                    return;
+                }
+                if !get_ppid(&proc_dir).is_some_and(|ppid| self.steam_pids.contains(&ppid)) {
+                    return;
+                }
+                if let Some(app_id) = get_app_id(&proc_dir) {
+                    self.start(db, timestamp, pid, app_id);
+                }
+            }
+            procmon::ProcEvent::Exit { pid, timestamp } => {
+                if self.steam_pids.remove(&pid) {
+                    info!(
+                        "steam pid={pid} exited, {} games keep running",
+                        self.games.len()
+                    );
+                } else {
+                    self.stop(db, timestamp, pid);
+                }
+            }
+            procmon::ProcEvent::Lost => {
+                warn!("process events were lost, rescanning");
+                self.rescan(db, now);
+            }
+        }
+    }
+}
+
+/// Adds the time since the previous tick to apps running during both ticks,
+/// nothing is added after gaps longer than `max_duration`, which the suspend
+/// check records as suspends.
+pub fn get_update_func(
+    max_duration: Duration,
+    ref_db: Rc<RefCell<db::DeckDB>>,
+    ref_tracker: Rc<RefCell<Tracker>>,
+) -> impl FnMut(SystemTime) {
+    let mut prev_ts: Option<SystemTime> = None;
+    move |now| {
+        let mut db = ref_db.borrow_mut();
+        let mut tracker = ref_tracker.borrow_mut();
+        let prev_apps = tracker.running_apps();
+
+        if let Some(conn) = tracker.connector.as_mut() {
+            match conn.read_events() {
+                Ok(events) => events
+                    .into_iter()
+                    .for_each(|event| tracker.handle(&mut db, now, event)),
+                Err(err) => {
+                    warn!("proc connector error ({err}), falling back to polling");
+                    tracker.connector = None;
+                }
+            }
+        }
+
+        if tracker.find_steam() || tracker.connector.is_none() {
+            tracker.rescan(&mut db, now);
+        }
+
+        let unix_ts = |ts: SystemTime| {
+            ts.duration_since(UNIX_EPOCH)
+                .expect("time ne tuda")
+                .as_secs()
+        };
+        let value = match prev_ts {
+            Some(prev_ts)
+                if now
+                    .duration_since(prev_ts)
+                    .is_ok_and(|elapsed| elapsed <= max_duration) =>
+            {
+                unix_ts(now) - unix_ts(prev_ts)
+            }
+            Some(_) => {
+                debug!("not counting the time since the last tick");
+                0
+            }
+            None => 0,
+        };
+        tracker
+            .running_apps()
+            .intersection(&prev_apps)
+            .for_each(|&app_id| db.update(app_id, now, value));
+        prev_ts = Some(now);
+        tracker.last_ts = cmp::max(now, tracker.last_ts);
+    }
 }
 
 pub fn get_suspend_check_func(
@@ -99,6 +396,244 @@ pub fn get_suspend_check_func(
     }
 }
 
+pub fn get_user_check_func(
+    home: steam::SteamHome,
+    ref_db: Rc<RefCell<db::DeckDB>>,
+) -> impl FnMut(SystemTime) {
+    let mut mtime = None;
+    move |_| {
+        let new_mtime = home.mtime();
+        if new_mtime != mtime {
+            mtime = new_mtime;
+            let user = home.active_user();
+            ref_db
+                .borrow_mut()
+                .set_user(user.as_ref())
+                .expect("set user error");
+        }
+    }
+}
+
 pub fn get_commit_func(ref_db: Rc<RefCell<db::DeckDB>>) -> impl FnMut(SystemTime) {
     move |x| ref_db.borrow_mut().commit(x).expect("commit error")
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{env, os::unix::fs::symlink};
+
+    #[test]
+    fn steam_clients() {
+        let proc_root = env::temp_dir().join(format!("decktime-proc-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&proc_root);
+        for (pid, exe) in [
+            ("100", "/usr/bin/bash"),
+            ("200", "/home/deck/.local/share/Steam/ubuntu12_32/steam"),
+            (
+                "300",
+                "/home/deck/.local/share/Steam/ubuntu12_64/steamwebhelper",
+            ),
+            ("400", "/app/.local/share/Steam/ubuntu12_32/steam (deleted)"),
+            ("500", "/home/deck/steam"),
+            ("self", "/home/deck/.local/share/Steam/ubuntu12_32/steam"),
+        ] {
+            fs::create_dir_all(proc_root.join(pid)).unwrap();
+            symlink(exe, proc_root.join(pid).join("exe")).unwrap();
+            fs::write(proc_root.join(pid).join("comm"), "steam\n").unwrap();
+        }
+        // Kernel threads have no executable and may have an empty name.
+        fs::create_dir_all(proc_root.join("2")).unwrap();
+        fs::write(proc_root.join("2").join("comm"), "").unwrap();
+
+        assert_eq!(find_steam_pids(&proc_root), vec![200, 400]);
+        assert!(find_steam_pids(&proc_root.join("missing")).is_empty());
+        fs::remove_dir_all(&proc_root).unwrap();
+    }
+
+    /// Creates `<proc_root>/<pid>` with the files the tracker reads.
+    pub(crate) fn add_process(proc_root: &Path, pid: u32, ppid: u32, exe: &str, cmdline: &str) {
+        let proc_dir = proc_root.join(pid.to_string());
+        fs::create_dir_all(proc_dir.join("task").join(pid.to_string())).unwrap();
+        symlink(exe, proc_dir.join("exe")).unwrap();
+        fs::write(proc_dir.join("stat"), format!("{pid} (a b) S {ppid} 1 1")).unwrap();
+        fs::write(
+            proc_dir.join("cmdline"),
+            cmdline.replace(' ', "\x00") + "\x00",
+        )
+        .unwrap();
+        let parent_task = proc_root
+            .join(ppid.to_string())
+            .join("task")
+            .join(ppid.to_string());
+        if parent_task.exists() {
+            let children = fs::read_to_string(parent_task.join("children")).unwrap_or_default();
+            fs::write(parent_task.join("children"), format!("{children}{pid} ")).unwrap();
+        }
+    }
+
+    pub(crate) type Recorded = Rc<RefCell<Vec<(Option<db::AppId>, db::EventType)>>>;
+
+    pub(crate) fn record_events(db: &mut db::DeckDB) -> Recorded {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let ref_events = Rc::clone(&events);
+        db.subscribe(Box::new(move |event| {
+            ref_events
+                .borrow_mut()
+                .push((event.app_id, event.event_type))
+        }));
+        events
+    }
+
+    #[test]
+    fn exec_exit() {
+        let proc_root = env::temp_dir().join(format!("decktime-exec-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&proc_root);
+        // Pids above the largest possible pid_max, no pidfd is opened for them.
+        let steam = "/home/deck/.local/share/Steam/ubuntu12_32/steam";
+        add_process(&proc_root, 5000001, 1, steam, "steam");
+        add_process(
+            &proc_root,
+            5000002,
+            5000001,
+            "/usr/bin/game",
+            "reaper SteamLaunch AppId=42 --",
+        );
+        add_process(
+            &proc_root,
+            5000003,
+            1,
+            "/usr/bin/game",
+            "reaper SteamLaunch AppId=43 --",
+        );
+
+        let mut db = db::DeckDB::build(":memory:", UNIX_EPOCH, None).unwrap();
+        let events = record_events(&mut db);
+        let mut tracker = Tracker::build(Monitor::Poll, &proc_root);
+        let at = |n| UNIX_EPOCH + Duration::from_secs(n);
+        for (pid, n) in [(5000001, 10), (5000002, 20), (5000003, 30)] {
+            let event = procmon::ProcEvent::Exec {
+                pid,
+                timestamp: at(n),
+            };
+            tracker.handle(&mut db, at(n), event);
+        }
+        assert_eq!(tracker.running_apps(), HashSet::from([42]));
+        for (pid, n) in [(5000003, 40), (5000002, 50)] {
+            let event = procmon::ProcEvent::Exit {
+                pid,
+                timestamp: at(n),
+            };
+            tracker.handle(&mut db, at(n), event);
+        }
+        assert!(tracker.running_apps().is_empty());
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                (Some(42), db::EventType::Started),
+                (Some(42), db::EventType::Stopped)
+            ]
+        );
+        fs::remove_dir_all(&proc_root).unwrap();
+    }
+
+    #[test]
+    fn late_exit() {
+        let proc_root = env::temp_dir().join(format!("decktime-late-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&proc_root);
+        let steam = "/home/deck/.local/share/Steam/ubuntu12_32/steam";
+        add_process(&proc_root, 5000001, 1, steam, "steam");
+        add_process(
+            &proc_root,
+            5000002,
+            5000001,
+            "/usr/bin/game",
+            "reaper SteamLaunch AppId=42 --",
+        );
+
+        let mut db = db::DeckDB::build(":memory:", UNIX_EPOCH, None).unwrap();
+        let events = record_events(&mut db);
+        let mut tracker = Tracker::build(Monitor::Poll, &proc_root);
+        let at = |n| UNIX_EPOCH + Duration::from_secs(n);
+        for (pid, n) in [(5000001, 10), (5000002, 20)] {
+            let event = procmon::ProcEvent::Exec {
+                pid,
+                timestamp: at(n),
+            };
+            tracker.handle(&mut db, at(n), event);
+        }
+        // The game exits at 30, but the event is read after a pause at 40.
+        db.set_paused(at(40), true).unwrap();
+        let event = procmon::ProcEvent::Exit {
+            pid: 5000002,
+            timestamp: at(30),
+        };
+        tracker.handle(&mut db, at(41), event);
+
+        assert_eq!(db.count_events(db::EventType::TrackingPaused).unwrap(), 1);
+        let stopped = events.borrow().last().copied();
+        assert_eq!(stopped, Some((Some(42), db::EventType::Stopped)));
+        assert_eq!(db.last_timestamp(), at(40));
+        db.flush(at(50)).unwrap();
+        fs::remove_dir_all(&proc_root).unwrap();
+    }
+
+    #[test]
+    fn steam_restart() {
+        let proc_root = env::temp_dir().join(format!("decktime-restart-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&proc_root);
+        let steam = "/home/deck/.local/share/Steam/ubuntu12_32/steam";
+        add_process(&proc_root, 5000001, 1, steam, "steam");
+        add_process(
+            &proc_root,
+            5000002,
+            5000001,
+            "/usr/bin/game",
+            "reaper SteamLaunch AppId=42 --",
+        );
+
+        let mut db = db::DeckDB::build(":memory:", UNIX_EPOCH, None).unwrap();
+        let events = record_events(&mut db);
+        let ref_db = Rc::new(RefCell::new(db));
+        let ref_tracker = Rc::new(RefCell::new(Tracker::build(Monitor::Poll, &proc_root)));
+        let mut update = get_update_func(
+            Duration::from_secs(2),
+            Rc::clone(&ref_db),
+            Rc::clone(&ref_tracker),
+        );
+        let at = |n| UNIX_EPOCH + Duration::from_secs(n);
+        update(at(10));
+        assert_eq!(ref_tracker.borrow().running_apps(), HashSet::from([42]));
+
+        // A new client starts a game before the old one exits, which keeps
+        // running.
+        add_process(&proc_root, 5000003, 1, steam, "steam");
+        add_process(
+            &proc_root,
+            5000004,
+            5000003,
+            "/usr/bin/game",
+            "reaper SteamLaunch AppId=43 --",
+        );
+        update(at(11));
+        assert_eq!(ref_tracker.borrow().running_apps(), HashSet::from([42, 43]));
+        fs::remove_dir_all(proc_root.join("5000001")).unwrap();
+        update(at(12));
+        assert_eq!(ref_tracker.borrow().steam_pids, HashSet::from([5000003]));
+        assert_eq!(ref_tracker.borrow().running_apps(), HashSet::from([42, 43]));
+
+        fs::remove_dir_all(proc_root.join("5000002")).unwrap();
+        update(at(13));
+        assert_eq!(ref_tracker.borrow().running_apps(), HashSet::from([43]));
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                (Some(42), db::EventType::Started),
+                (Some(43), db::EventType::Started),
+                (Some(42), db::EventType::Stopped)
+            ]
+        );
+        ref_db.borrow_mut().flush(at(14)).unwrap();
+        fs::remove_dir_all(&proc_root).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+use log::debug;
+use std::{
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    time::{Duration, SystemTime},
+};
+
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+
+const PROC_EVENT_EXEC: u32 = 0x0000_0002;
+const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+
+const NLMSG_HDRLEN: usize = 16;
+const CN_MSG_LEN: usize = 20;
+const PROC_EVENT_HDRLEN: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum ProcEvent {
+    Exec { pid: u32, timestamp: SystemTime },
+    Exit { pid: u32, timestamp: SystemTime },
+    Lost,
+}
+
+#[derive(Debug, PartialEq)]
+struct RawEvent {
+    what: u32,
+    pid: u32,
+    tgid: u32,
+    timestamp_ns: u64,
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(buf.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn read_u64(buf: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_ne_bytes(buf.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+fn parse_message(buf: &[u8]) -> Option<RawEvent> {
+    let len = read_u32(buf, 0)? as usize;
+    let buf = buf.get(..len)?;
+
+    let idx = read_u32(buf, NLMSG_HDRLEN)?;
+    let val = read_u32(buf, NLMSG_HDRLEN + 4)?;
+    if idx != CN_IDX_PROC || val != CN_VAL_PROC {
+        return None;
+    }
+
+    let event = NLMSG_HDRLEN + CN_MSG_LEN;
+    let data = event + PROC_EVENT_HDRLEN;
+    Some(RawEvent {
+        what: read_u32(buf, event)?,
+        timestamp_ns: read_u64(buf, event + 8)?,
+        pid: read_u32(buf, data)?,
+        tgid: read_u32(buf, data + 4)?,
+    })
+}
+
+fn monotonic_now() -> Duration {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// Subscription to exec and exit events of the kernel proc connector.
+///
+/// Requires `CAP_NET_ADMIN`, so [`ProcConnector::open`] is expected to fail
+/// for unprivileged users.
+pub struct ProcConnector {
+    fd: OwnedFd,
+}
+
+impl ProcConnector {
+    pub fn open() -> io::Result<ProcConnector> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_CONNECTOR,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        addr.nl_groups = CN_IDX_PROC;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let len = NLMSG_HDRLEN + CN_MSG_LEN + 4;
+        let mut msg = Vec::with_capacity(len);
+        msg.extend((len as u32).to_ne_bytes());
+        msg.extend((libc::NLMSG_DONE as u16).to_ne_bytes());
+        msg.extend(0u16.to_ne_bytes());
+        msg.extend(0u32.to_ne_bytes());
+        msg.extend(0u32.to_ne_bytes());
+        msg.extend(CN_IDX_PROC.to_ne_bytes());
+        msg.extend(CN_VAL_PROC.to_ne_bytes());
+        msg.extend(0u32.to_ne_bytes());
+        msg.extend(0u32.to_ne_bytes());
+        msg.extend(4u16.to_ne_bytes());
+        msg.extend(0u16.to_ne_bytes());
+        msg.extend(PROC_CN_MCAST_LISTEN.to_ne_bytes());
+        let ret = unsafe { libc::send(fd.as_raw_fd(), msg.as_ptr().cast(), msg.len(), 0) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ProcConnector { fd })
+    }
+
+    /// Drains all pending events without blocking.
+    pub fn read_events(&mut self) -> io::Result<Vec<ProcEvent>> {
+        let mut events = Vec::new();
+        let mut buf = [0u8; 1024];
+
+        let now = SystemTime::now();
+        let now_mono = monotonic_now();
+        let to_system_time = |timestamp_ns| {
+            now.checked_sub(now_mono.saturating_sub(Duration::from_nanos(timestamp_ns)))
+                .unwrap_or(now)
+        };
+
+        loop {
+            let ret =
+                unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EAGAIN) => return Ok(events),
+                    Some(libc::EINTR) => continue,
+                    Some(libc::ENOBUFS) => {
+                        debug!("proc connector buffer overrun");
+                        events.push(ProcEvent::Lost);
+                        continue;
+                    }
+                    _ => return Err(err),
+                }
+            }
+
+            let Some(event) = parse_message(&buf[..ret as usize]) else {
+                continue;
+            };
+            let timestamp = to_system_time(event.timestamp_ns);
+            match event.what {
+                PROC_EVENT_EXEC => events.push(ProcEvent::Exec {
+                    pid: event.tgid,
+                    timestamp,
+                }),
+                PROC_EVENT_EXIT if event.pid == event.tgid => events.push(ProcEvent::Exit {
+                    pid: event.tgid,
+                    timestamp,
+                }),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_exit_message() {
+        let mut buf = Vec::new();
+        buf.extend([0u8; 16]);
+        buf.extend(CN_IDX_PROC.to_ne_bytes());
+        buf.extend(CN_VAL_PROC.to_ne_bytes());
+        buf.extend([0u8; 12]);
+        buf.extend(PROC_EVENT_EXIT.to_ne_bytes());
+        buf.extend(3u32.to_ne_bytes());
+        buf.extend(123_456_789u64.to_ne_bytes());
+        buf.extend(42u32.to_ne_bytes());
+        buf.extend(41u32.to_ne_bytes());
+        buf.extend([0u8; 16]);
+        let len = buf.len() as u32;
+        buf[..4].copy_from_slice(&len.to_ne_bytes());
+
+        assert_eq!(
+            parse_message(&buf),
+            Some(RawEvent {
+                what: PROC_EVENT_EXIT,
+                pid: 42,
+                tgid: 41,
+                timestamp_ns: 123_456_789,
+            })
+        );
+        assert_eq!(parse_message(&buf[..40]), None);
+    }
+}
@@ -0,0 +1,229 @@
+use crate::db::{AppId, DeckDB};
+use chrono::{DateTime, NaiveDate};
+use rusqlite::{Connection, Result};
+use std::collections::BTreeMap;
+
+pub struct AppTotal {
+    pub app_id: AppId,
+    pub alias: Option<String>,
+    pub seconds: u64,
+}
+
+/// Total playtime per app, optionally limited to a single Steam account
+/// matched by steam id, account name or persona name.
+pub fn app_totals(conn: &Connection, user: Option<&str>) -> Result<Vec<AppTotal>> {
+    let mut stmt = conn.prepare(
+        "select app_id, alias, sum(value) from timeline \
+            join objects on timeline.object_id = objects.object_id \
+            left join users on timeline.user_id = users.user_id \
+            where ?1 is null \
+                or cast(users.steam_id as text) = ?1 \
+                or users.account_name = ?1 collate nocase \
+                or users.persona_name = ?1 collate nocase \
+            group by timeline.object_id \
+            order by 3 desc",
+    )?;
+    let totals = stmt
+        .query_map((user,), |row| {
+            Ok(AppTotal {
+                app_id: row.get(0)?,
+                alias: row.get(1)?,
+                seconds: row.get(2)?,
+            })
+        })?
+        .collect();
+    totals
+}
+
+/// Distributes the `value` seconds of the bucket `[start, start + len)` over
+/// the local days it overlaps, assuming they were spread evenly.
+pub fn split_days(start: i64, len: i64, utc_offset: i32, value: u64) -> Vec<(NaiveDate, u64)> {
+    let date = |day: i64| {
+        DateTime::from_timestamp(day * 86400, 0)
+            .unwrap()
+            .date_naive()
+    };
+    let local = start + utc_offset as i64;
+    let day = local.div_euclid(86400);
+    let midnight = (day + 1) * 86400;
+    if local + len <= midnight {
+        return vec![(date(day), value)];
+    }
+    let first = value * (midnight - local) as u64 / len as u64;
+    vec![(date(day), first), (date(day + 1), value - first)]
+}
+
+/// Distributes the `value` seconds of the bucket `[start, start + len)` over
+/// the local hours it overlaps, assuming they were spread evenly. Hours are
+/// numbered from the local epoch, so `hour % 24` is the hour of the day.
+pub fn split_hours(start: i64, len: i64, utc_offset: i32, value: u64) -> Vec<(i64, u64)> {
+    let mut local = start + utc_offset as i64;
+    let end = local + len;
+    let mut left = value;
+    let mut hours = Vec::new();
+    while local < end {
+        let hour = local.div_euclid(3600);
+        let next = end.min((hour + 1) * 3600);
+        let secs = match next == end {
+            true => left,
+            false => value * (next - local) as u64 / len as u64,
+        };
+        hours.push((hour, secs));
+        left -= secs;
+        local = next;
+    }
+    hours
+}
+
+/// Total playtime per local day, using the UTC offset stored with each bucket
+/// or `fallback` for buckets recorded before offsets were stored.
+pub fn daily_totals(
+    conn: &Connection,
+    user: Option<&str>,
+    fallback: impl Fn(u64) -> i32,
+) -> Result<Vec<(NaiveDate, u64)>> {
+    let mut stmt = conn.prepare(
+        "select timestamp, utc_offset, sum(value) from timeline \
+            left join users on timeline.user_id = users.user_id \
+            where ?1 is null \
+                or cast(users.steam_id as text) = ?1 \
+                or users.account_name = ?1 collate nocase \
+                or users.persona_name = ?1 collate nocase \
+            group by timestamp, utc_offset",
+    )?;
+    let bucket_secs = DeckDB::bucket_secs(conn)?;
+    let mut days = BTreeMap::new();
+    let mut rows = stmt.query((user,))?;
+    while let Some(row) = rows.next()? {
+        let timestamp: u64 = row.get(0)?;
+        let utc_offset: Option<i32> = row.get(1)?;
+        let start = timestamp * bucket_secs;
+        let utc_offset = utc_offset.unwrap_or_else(|| fallback(start));
+        for (date, value) in split_days(start as i64, bucket_secs as i64, utc_offset, row.get(2)?) {
+            *days.entry(date).or_insert(0) += value;
+        }
+    }
+    Ok(days.into_iter().collect())
+}
+
+pub fn format_duration(seconds: u64) -> String {
+    format!("{}h {:02}m", seconds / 3600, seconds / 60 % 60)
+}
+
+pub fn print_app_totals(totals: &[AppTotal]) {
+    println!("{:>10}  {:<32}  {:>10}", "app_id", "alias", "playtime");
+    for total in totals {
+        println!(
+            "{:>10}  {:<32}  {:>10}",
+            total.app_id,
+            total.alias.as_deref().unwrap_or("-"),
+            format_duration(total.seconds)
+        );
+    }
+}
+
+pub fn print_daily_totals(totals: &[(NaiveDate, u64)]) {
+    println!("{:<10}  {:>10}", "date", "playtime");
+    for (date, seconds) in totals {
+        println!("{date:<10}  {:>10}", format_duration(*seconds));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_filter() {
+        let conn = DeckDB::open(":memory:").unwrap();
+        conn.execute_batch(
+            "insert into objects (object_id, app_id, alias) values (1, 10, 'Game'), (2, 20, null); \
+            insert into users (user_id, steam_id, account_name, persona_name) \
+                values (1, 76561198000000001, 'parent', 'Mom'), (2, 76561198000000002, 'kid', null); \
+            insert into timeline (timestamp, object_id, user_id, value) values \
+                (1, 1, 1, 600), (2, 1, 2, 1200), (2, 2, 2, 60), (3, 2, 0, 30);",
+        )
+        .unwrap();
+
+        let totals = |user| {
+            app_totals(&conn, user)
+                .unwrap()
+                .into_iter()
+                .map(|total| (total.app_id, total.seconds))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(totals(None), vec![(10, 1800), (20, 90)]);
+        assert_eq!(totals(Some("KID")), vec![(10, 1200), (20, 60)]);
+        assert_eq!(totals(Some("mom")), vec![(10, 600)]);
+        assert_eq!(
+            totals(Some("76561198000000002")),
+            vec![(10, 1200), (20, 60)]
+        );
+        assert_eq!(totals(Some("nobody")), vec![]);
+    }
+
+    #[test]
+    fn local_days() {
+        let conn = DeckDB::open(":memory:").unwrap();
+        conn.execute("insert into objects (object_id, app_id) values (1, 10)", ())
+            .unwrap();
+        let bucket = |y, m, d, h| {
+            NaiveDate::from_ymd_opt(y, m, d)
+                .unwrap()
+                .and_hms_opt(h, 0, 0)
+                .unwrap()
+                .and_utc()
+                .timestamp()
+                / 3600
+        };
+        let mut insert = conn
+            .prepare("insert into timeline (timestamp, object_id, value, utc_offset) values (?1, 1, ?2, ?3)")
+            .unwrap();
+        for (timestamp, value, utc_offset) in [
+            // Europe/Berlin springs forward at 01:00 UTC.
+            (bucket(2024, 3, 30, 22), 100, Some(3600)),
+            (bucket(2024, 3, 30, 23), 200, Some(3600)),
+            (bucket(2024, 3, 31, 1), 400, Some(7200)),
+            // and falls back at 01:00 UTC.
+            (bucket(2024, 10, 26, 21), 10, Some(7200)),
+            (bucket(2024, 10, 26, 22), 20, Some(7200)),
+            (bucket(2024, 10, 27, 1), 40, Some(3600)),
+            // Nepal, 23:45 local.
+            (bucket(2024, 6, 1, 18), 3600, Some(20700)),
+            // Newfoundland, 23:30 local, recorded without an offset.
+            (bucket(2024, 7, 1, 3), 600, None),
+        ] {
+            insert.execute((timestamp, value, utc_offset)).unwrap();
+        }
+
+        let date = |m, d| NaiveDate::from_ymd_opt(2024, m, d).unwrap();
+        assert_eq!(
+            daily_totals(&conn, None, |_| -12600).unwrap(),
+            vec![
+                (date(3, 30), 100),
+                (date(3, 31), 600),
+                (date(6, 1), 900),
+                (date(6, 2), 2700),
+                (date(6, 30), 300),
+                (date(7, 1), 300),
+                (date(10, 26), 10),
+                (date(10, 27), 60),
+            ]
+        );
+    }
+
+    #[test]
+    fn local_hours() {
+        // India is 5:30 ahead, an hour bucket ends half way through an hour.
+        assert_eq!(
+            split_hours(0, 3600, 19800, 3600),
+            vec![(5, 1800), (6, 1800)]
+        );
+        assert_eq!(split_hours(3600, 3600, 19800, 101), vec![(6, 50), (7, 51)]);
+        assert_eq!(
+            split_hours(0, 7200, 19800, 720),
+            vec![(5, 180), (6, 360), (7, 180)]
+        );
+        assert_eq!(split_hours(900, 900, -3600, 60), vec![(-1, 60)]);
+    }
+}
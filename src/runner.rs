@@ -0,0 +1,82 @@
+use log::{debug, error, warn};
+use std::{
+    cell::RefCell,
+    os::unix::process::CommandExt,
+    process::{Child, Command, Stdio},
+    rc::Rc,
+    time::{Duration, Instant, SystemTime},
+};
+
+struct Running {
+    command: String,
+    child: Child,
+    deadline: Instant,
+}
+
+/// Runs shell commands in the background so they never block the scheduler.
+#[derive(Default)]
+pub struct Runner {
+    running: Vec<Running>,
+}
+
+impl Runner {
+    pub fn spawn(&mut self, command: &str, envs: &[(&str, String)], timeout: Duration) {
+        debug!("running {command:?}");
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .envs(envs.iter().map(|(key, value)| (key, value)))
+            .stdin(Stdio::null())
+            .process_group(0)
+            .spawn();
+        match child {
+            Ok(child) => self.running.push(Running {
+                command: command.to_owned(),
+                child,
+                deadline: Instant::now() + timeout,
+            }),
+            Err(err) => error!("unable to run {command:?}: {err}"),
+        }
+    }
+
+    /// Collects finished commands and kills the ones past their deadline.
+    pub fn reap(&mut self) {
+        let now = Instant::now();
+        self.running.retain_mut(|running| {
+            match running.child.try_wait() {
+                Ok(Some(status)) if !status.success() => {
+                    warn!("{:?} exited with {status}", running.command)
+                }
+                Ok(Some(_)) => {}
+                Ok(None) if now >= running.deadline => {
+                    warn!("{:?} timed out, killing", running.command);
+                    unsafe { libc::kill(-(running.child.id() as libc::pid_t), libc::SIGKILL) };
+                    let _ = running.child.wait();
+                }
+                Ok(None) => return true,
+                Err(err) => error!("unable to wait for {:?}: {err}", running.command),
+            }
+            false
+        });
+    }
+}
+
+pub fn get_reap_func(ref_runner: Rc<RefCell<Runner>>) -> impl FnMut(SystemTime) {
+    move |_| ref_runner.borrow_mut().reap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn timeout() {
+        let mut runner = Runner::default();
+        runner.spawn("exit 0", &[], Duration::from_secs(10));
+        runner.spawn("sleep 10", &[], Duration::from_millis(100));
+        thread::sleep(Duration::from_millis(300));
+        runner.reap();
+        assert!(runner.running.is_empty());
+    }
+}
@@ -1,7 +1,46 @@
-use log::warn;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use chrono::{NaiveTime, Timelike};
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-type Callback = Box<dyn FnMut(SystemTime)>;
+pub type Callback = Box<dyn FnMut(SystemTime)>;
+
+/// Callbacks running longer than this are logged.
+const SLOW_CALLBACK: Duration = Duration::from_millis(100);
+
+/// When a task runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Schedule {
+    /// Repeatedly with a fixed delay, aligned to `UNIX_EPOCH`.
+    Every(Duration),
+    /// Once at the given time.
+    Once(SystemTime),
+    /// Every day at the given local time.
+    DailyAt(NaiveTime),
+}
+
+pub struct Task {
+    name: String,
+    schedule: Schedule,
+    jitter: Duration,
+    callback: Callback,
+}
+
+impl Task {
+    pub fn build(name: &str, schedule: Schedule, callback: Callback) -> Task {
+        Task {
+            name: name.to_owned(),
+            schedule,
+            jitter: Duration::ZERO,
+            callback,
+        }
+    }
+
+    /// Delays every run by a random duration of up to `jitter`.
+    pub fn jitter(self, jitter: Duration) -> Task {
+        Task { jitter, ..self }
+    }
+}
 
 fn get_next_ts(start: SystemTime, now: SystemTime, step: Duration) -> SystemTime {
     start
@@ -13,66 +52,379 @@ fn get_next_ts(start: SystemTime, now: SystemTime, step: Duration) -> SystemTime
         )
 }
 
+/// First time after `now` at the local time `at`.
+fn get_next_daily_ts(at: NaiveTime, now: SystemTime, utc_offset: fn(u64) -> i32) -> SystemTime {
+    let now = now.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let local = now + utc_offset(now as u64) as i64;
+    let mut next = local.div_euclid(86400) * 86400 + at.num_seconds_from_midnight() as i64;
+    if next <= local {
+        next += 86400;
+    }
+    // The offset may change until then, e.g. on DST transitions.
+    let guess = next - utc_offset(now as u64) as i64;
+    let next = next - utc_offset(guess as u64) as i64;
+    UNIX_EPOCH + Duration::from_secs(next.max(now + 1) as u64)
+}
+
+/// Xorshift generator for jitter, which does not need to be unpredictable.
+struct Rng(u64);
+
+impl Rng {
+    fn jitter(&mut self, max: Duration) -> Duration {
+        if max.is_zero() {
+            return Duration::ZERO;
+        }
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        Duration::from_millis(x % (max.as_millis() as u64 + 1))
+    }
+}
+
+#[derive(Default)]
+struct Timing {
+    runs: u64,
+    total: Duration,
+    last: Duration,
+    max: Duration,
+    last_drift: Duration,
+    max_drift: Duration,
+}
+
+/// Timing statistics of a task, durations are in microseconds.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TaskStats {
+    pub name: String,
+    pub next_run: u64,
+    pub runs: u64,
+    pub missed_ticks: u64,
+    pub last_us: u64,
+    pub average_us: u64,
+    pub max_us: u64,
+    /// How late the last run started after its scheduled time.
+    pub last_drift_us: u64,
+    pub max_drift_us: u64,
+}
+
 struct Timer {
-    delay: Duration,
+    task: Task,
+    /// Time of the next run without jitter.
+    slot: SystemTime,
     next_timestamp: SystemTime,
-    callback: Callback,
+    missed_ticks: u64,
+    timing: Timing,
 }
 
 impl Timer {
-    fn build_aligned(delay: Duration, callback: Callback, now: SystemTime) -> Timer {
+    fn build(task: Task, now: SystemTime, rng: &mut Rng, utc_offset: fn(u64) -> i32) -> Timer {
+        let slot = match task.schedule {
+            Schedule::Every(delay) => get_next_ts(UNIX_EPOCH, now, delay),
+            Schedule::Once(timestamp) => timestamp,
+            Schedule::DailyAt(at) => get_next_daily_ts(at, now, utc_offset),
+        };
         Timer {
-            delay,
-            next_timestamp: get_next_ts(UNIX_EPOCH, now, delay),
-            callback,
+            next_timestamp: slot + rng.jitter(task.jitter),
+            task,
+            slot,
+            missed_ticks: 0,
+            timing: Timing::default(),
         }
     }
 
-    fn check(&mut self, now: SystemTime) {
-        if self.next_timestamp <= now {
-            (self.callback)(now);
-            self.next_timestamp += self.delay;
-            if self.next_timestamp <= now {
-                warn!(
-                    "missed {}s",
-                    now.duration_since(self.next_timestamp).unwrap().as_secs()
-                );
-                self.next_timestamp = get_next_ts(self.next_timestamp, now, self.delay);
+    /// Runs the task if it is due, returns true if it will not run again.
+    ///
+    /// `now` is the time the scheduler woke up at `woke`, later tasks of the
+    /// same wake-up start late by the run time of the earlier ones.
+    fn check(
+        &mut self,
+        now: SystemTime,
+        woke: Instant,
+        rng: &mut Rng,
+        utc_offset: fn(u64) -> i32,
+    ) -> bool {
+        if self.next_timestamp > now {
+            return false;
+        }
+        trace!("running task {}", self.task.name);
+        let started = Instant::now();
+        let drift = (now + started.duration_since(woke))
+            .duration_since(self.next_timestamp)
+            .unwrap_or_default();
+        (self.task.callback)(now);
+        let elapsed = started.elapsed();
+
+        let timing = &mut self.timing;
+        timing.runs += 1;
+        timing.total += elapsed;
+        timing.last = elapsed;
+        timing.max = timing.max.max(elapsed);
+        timing.last_drift = drift;
+        timing.max_drift = timing.max_drift.max(drift);
+        if elapsed >= SLOW_CALLBACK {
+            warn!(
+                "task {} took {}ms, started {}ms late",
+                self.task.name,
+                elapsed.as_millis(),
+                drift.as_millis()
+            );
+        }
+
+        match self.task.schedule {
+            Schedule::Every(delay) => {
+                self.slot += delay;
+                if self.slot <= now {
+                    warn!(
+                        "task {} missed {}s",
+                        self.task.name,
+                        now.duration_since(self.slot).unwrap().as_secs()
+                    );
+                    let mut slot = get_next_ts(self.slot, now, delay);
+                    if slot <= now {
+                        slot += delay;
+                    }
+                    self.missed_ticks += slot
+                        .duration_since(self.slot)
+                        .unwrap()
+                        .div_duration_f64(delay)
+                        .round() as u64;
+                    self.slot = slot;
+                }
             }
+            Schedule::Once(_) => return true,
+            Schedule::DailyAt(at) => self.slot = get_next_daily_ts(at, now, utc_offset),
         }
+        self.next_timestamp = self.slot + rng.jitter(self.task.jitter);
+        false
     }
 }
 
 pub struct Scheduler {
     timers: Vec<Timer>,
     next_timestamp: Option<SystemTime>,
+    rng: Rng,
+    utc_offset: fn(u64) -> i32,
+    /// Missed ticks of removed tasks.
+    missed_ticks: u64,
 }
 
 impl Scheduler {
-    pub fn build_aligned(params: Vec<(Duration, Callback)>, now: SystemTime) -> Scheduler {
-        let timers: Vec<Timer> = params
-            .into_iter()
-            .map(|(delay, callback)| Timer::build_aligned(delay, callback, now))
-            .collect();
-        let next_timestamp = timers.iter().map(|timer| timer.next_timestamp).min();
-        Scheduler {
-            timers,
-            next_timestamp,
+    /// Builds a scheduler running daily tasks at the local time given by
+    /// `utc_offset`, the UTC offset in seconds at a unix time.
+    pub fn build(tasks: Vec<Task>, now: SystemTime, utc_offset: fn(u64) -> i32) -> Scheduler {
+        let seed = now.duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64;
+        let mut sched = Scheduler {
+            timers: Vec::new(),
+            next_timestamp: None,
+            rng: Rng(seed | 1),
+            utc_offset,
+            missed_ticks: 0,
+        };
+        for task in tasks {
+            sched.add(task, now);
         }
+        sched
+    }
+
+    /// Adds a task, replacing the task with the same name if there is one.
+    fn add(&mut self, task: Task, now: SystemTime) {
+        self.remove(&task.name);
+        let timer = Timer::build(task, now, &mut self.rng, self.utc_offset);
+        self.timers.push(timer);
+        self.update_next_timestamp();
+    }
+
+    /// Removes the task with the given name, returns false if there is none.
+    fn remove(&mut self, name: &str) -> bool {
+        let Some(i) = self.timers.iter().position(|timer| timer.task.name == name) else {
+            return false;
+        };
+        self.missed_ticks += self.timers.remove(i).missed_ticks;
+        self.update_next_timestamp();
+        true
+    }
+
+    fn update_next_timestamp(&mut self) {
+        self.next_timestamp = self.timers.iter().map(|timer| timer.next_timestamp).min();
     }
 
     pub fn run_pending(&mut self, now: SystemTime) {
-        self.next_timestamp = self
-            .timers
-            .iter_mut()
-            .map(|timer| {
-                timer.check(now);
-                timer.next_timestamp
-            })
-            .min();
+        let (rng, utc_offset) = (&mut self.rng, self.utc_offset);
+        let woke = Instant::now();
+        let mut missed_ticks = 0;
+        self.timers.retain_mut(|timer| {
+            let done = timer.check(now, woke, rng, utc_offset);
+            if done {
+                missed_ticks += timer.missed_ticks;
+            }
+            !done
+        });
+        self.missed_ticks += missed_ticks;
+        self.update_next_timestamp();
+    }
+
+    /// Number of ticks skipped because callbacks ran late.
+    pub fn missed_ticks(&self) -> u64 {
+        self.missed_ticks
+            + self
+                .timers
+                .iter()
+                .map(|timer| timer.missed_ticks)
+                .sum::<u64>()
     }
 
     pub fn get_next_timestamp(&self) -> Option<SystemTime> {
-        return self.next_timestamp;
+        self.next_timestamp
+    }
+
+    pub fn stats(&self) -> Vec<TaskStats> {
+        let micros = |duration: Duration| duration.as_micros() as u64;
+        self.timers
+            .iter()
+            .map(|timer| TaskStats {
+                name: timer.task.name.clone(),
+                next_run: timer
+                    .next_timestamp
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+                runs: timer.timing.runs,
+                missed_ticks: timer.missed_ticks,
+                last_us: micros(timer.timing.last),
+                average_us: micros(timer.timing.total) / timer.timing.runs.max(1),
+                max_us: micros(timer.timing.max),
+                last_drift_us: micros(timer.timing.last_drift),
+                max_drift_us: micros(timer.timing.max_drift),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc};
+
+    fn time(n: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(n)
+    }
+
+    fn secs(timestamp: SystemTime) -> u64 {
+        timestamp.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    /// Scheduler driven by a fake clock, recording the runs of its tasks.
+    struct Harness {
+        sched: Scheduler,
+        runs: Rc<RefCell<Vec<(&'static str, u64)>>>,
+        now: SystemTime,
+    }
+
+    impl Harness {
+        fn build(now: u64) -> Harness {
+            Harness {
+                sched: Scheduler::build(Vec::new(), time(now), |_| 3600),
+                runs: Rc::new(RefCell::new(Vec::new())),
+                now: time(now),
+            }
+        }
+
+        fn add(&mut self, name: &'static str, schedule: Schedule, jitter: u64) {
+            let runs = Rc::clone(&self.runs);
+            let task = Task::build(
+                name,
+                schedule,
+                Box::new(move |now| runs.borrow_mut().push((name, secs(now)))),
+            );
+            self.sched
+                .add(task.jitter(Duration::from_secs(jitter)), self.now);
+        }
+
+        /// Wakes up on time for every run until `until`.
+        fn run_until(&mut self, until: u64) {
+            while let Some(next) = self
+                .sched
+                .get_next_timestamp()
+                .filter(|&next| next <= time(until))
+            {
+                self.now = next;
+                self.sched.run_pending(next);
+            }
+            self.now = time(until);
+        }
+
+        /// Wakes up late at `at`, e.g. after a stall.
+        fn wake_at(&mut self, at: u64) {
+            self.now = time(at);
+            self.sched.run_pending(self.now);
+        }
+
+        fn runs(&self) -> Vec<(&'static str, u64)> {
+            self.runs.borrow_mut().drain(..).collect()
+        }
+    }
+
+    #[test]
+    fn missed_ticks() {
+        let mut harness = Harness::build(95);
+        harness.add("every", Schedule::Every(Duration::from_secs(10)), 0);
+        harness.run_until(120);
+        assert_eq!(
+            harness.runs(),
+            vec![("every", 100), ("every", 110), ("every", 120)]
+        );
+
+        harness.wake_at(155);
+        assert_eq!(harness.runs(), vec![("every", 155)]);
+        assert_eq!(harness.sched.missed_ticks(), 2);
+        let stats = harness.sched.stats();
+        assert_eq!((stats[0].runs, stats[0].missed_ticks), (4, 2));
+        assert_eq!(stats[0].next_run, 160);
+        assert!((25_000_000..26_000_000).contains(&stats[0].last_drift_us));
+        assert_eq!(stats[0].max_drift_us, stats[0].last_drift_us);
+        assert_eq!(harness.sched.get_next_timestamp(), Some(time(160)));
+
+        // Exactly on a later slot, which must not run twice.
+        harness.wake_at(180);
+        assert_eq!(harness.sched.missed_ticks(), 4);
+        harness.run_until(190);
+        assert_eq!(harness.runs(), vec![("every", 180), ("every", 190)]);
+    }
+
+    #[test]
+    fn tasks() {
+        let mut harness = Harness::build(1);
+        harness.add("once", Schedule::Once(time(30)), 0);
+        harness.add(
+            "nightly",
+            Schedule::DailyAt(NaiveTime::from_hms_opt(4, 0, 0).unwrap()),
+            0,
+        );
+        harness.add("jitter", Schedule::Every(Duration::from_secs(100)), 20);
+        harness.add("removed", Schedule::Every(Duration::from_secs(10)), 0);
+        harness.run_until(25);
+        assert!(harness.sched.remove("removed"));
+        assert!(!harness.sched.remove("removed"));
+        // Replaces the first one-shot task.
+        harness.add("once", Schedule::Once(time(50)), 0);
+        harness.run_until(2 * 86400);
+
+        let runs = harness.runs();
+        assert_eq!(&runs[..3], [("removed", 10), ("removed", 20), ("once", 50)]);
+        assert_eq!(
+            runs.iter()
+                .filter(|(name, _)| *name == "nightly")
+                .collect::<Vec<_>>(),
+            [&("nightly", 3 * 3600), &("nightly", 86400 + 3 * 3600)]
+        );
+        let jittered: Vec<u64> = runs
+            .iter()
+            .filter(|(name, _)| *name == "jitter")
+            .map(|(_, ts)| ts % 100)
+            .collect();
+        assert!(jittered.len() >= 1727);
+        assert!(jittered.iter().all(|&delay| delay <= 20));
+        assert!(jittered.iter().any(|&delay| delay != jittered[0]));
     }
 }
@@ -0,0 +1,819 @@
+use crate::db::{self, AppId, DeckDB, EventType, Source, UserId, THIS_APP_ID};
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use log::info;
+use rusqlite::{Connection, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Half-open interval of unix seconds.
+pub type Interval = (u64, u64);
+
+/// Time window to remove from the recorded playtime.
+#[derive(Debug, Clone, Serialize, Deserialize, clap::Args)]
+pub struct Exclusion {
+    #[arg(long, value_name = "TIME", value_parser = parse_unix_ts)]
+    #[arg(help = "Start of the window, e.g. \"2024-05-01 18:00\"")]
+    pub from: u64,
+    #[arg(long, value_name = "TIME", value_parser = parse_unix_ts)]
+    #[arg(help = "End of the window")]
+    pub to: u64,
+    #[arg(long, value_name = "APP_ID", help = "Only exclude this app")]
+    pub app: Option<AppId>,
+}
+
+impl Exclusion {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.from < self.to {
+            true => Ok(()),
+            false => Err("the window must end after it starts".to_owned()),
+        }
+    }
+}
+
+/// Session played outside of the device.
+#[derive(Debug, Clone, Serialize, Deserialize, clap::Args)]
+pub struct NewSession {
+    #[arg(long, value_name = "APP", help = "App id or alias")]
+    pub app: String,
+    #[arg(long, value_name = "TIME", value_parser = parse_unix_ts)]
+    #[arg(help = "Start of the session, e.g. \"2024-05-01 18:00\"")]
+    pub start: u64,
+    #[arg(long, value_name = "TIME", value_parser = parse_unix_ts)]
+    #[arg(help = "End of the session")]
+    pub end: u64,
+    #[arg(long, value_name = "USER")]
+    #[arg(help = "Steam account that played (id, account or persona name) [default: active]")]
+    pub user: Option<String>,
+}
+
+impl NewSession {
+    /// Records the session for its user or else `active_user`, returns its id.
+    pub fn add(&self, conn: &Connection, active_user: UserId, now: u64) -> Result<u64, String> {
+        let app_id = resolve_app(conn, &self.app)?;
+        let user_id = match &self.user {
+            Some(user) => resolve_user(conn, user)?,
+            None => active_user,
+        };
+        add_session(conn, app_id, user_id, self.start, self.end, now)
+    }
+}
+
+/// Change of a manually logged session, unset fields are kept.
+#[derive(Debug, Clone, Serialize, Deserialize, clap::Args)]
+pub struct SessionChange {
+    #[arg(long, help = "Session id")]
+    pub id: u64,
+    #[arg(long, value_name = "APP", help = "App id or alias")]
+    pub app: Option<String>,
+    #[arg(long, value_name = "TIME", value_parser = parse_unix_ts)]
+    #[arg(help = "Start of the session")]
+    pub start: Option<u64>,
+    #[arg(long, value_name = "TIME", value_parser = parse_unix_ts)]
+    #[arg(help = "End of the session")]
+    pub end: Option<u64>,
+}
+
+impl SessionChange {
+    pub fn apply(&self, conn: &Connection, now: u64) -> Result<(), String> {
+        let app_id = match &self.app {
+            Some(app) => Some(resolve_app(conn, app)?),
+            None => None,
+        };
+        edit_session(conn, self.id, app_id, self.start, self.end, now)
+    }
+}
+
+/// Parses `YYYY-MM-DD HH:MM[:SS]` in local time, RFC 3339 or unix seconds.
+pub fn parse_timestamp(s: &str) -> Result<SystemTime, String> {
+    let s = s.trim();
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(s) {
+        return Ok(datetime.into());
+    }
+    [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+    .and_then(|naive| Local.from_local_datetime(&naive).earliest())
+    .map(SystemTime::from)
+    .ok_or_else(|| format!("invalid timestamp {s:?}"))
+}
+
+pub fn parse_unix_ts(s: &str) -> Result<u64, String> {
+    parse_timestamp(s)?
+        .duration_since(UNIX_EPOCH)
+        .map(|ts| ts.as_secs())
+        .map_err(|_| format!("invalid timestamp {s:?}"))
+}
+
+/// Removes the parts of `intervals` covered by `cuts`.
+fn subtract(intervals: Vec<Interval>, cuts: &[Interval]) -> Vec<Interval> {
+    cuts.iter()
+        .fold(intervals, |intervals, &(cut_start, cut_end)| {
+            intervals
+                .into_iter()
+                .flat_map(|(start, end)| {
+                    [(start, end.min(cut_start)), (start.max(cut_end), end)]
+                        .into_iter()
+                        .filter(|(start, end)| start < end)
+                })
+                .collect()
+        })
+}
+
+fn clip(intervals: Vec<Interval>, from: u64, to: u64) -> Vec<Interval> {
+    intervals
+        .into_iter()
+        .map(|(start, end)| (start.max(from), end.min(to)))
+        .filter(|(start, end)| start < end)
+        .collect()
+}
+
+/// Seconds of `intervals` per `timeline` bucket.
+fn split_buckets(intervals: &[Interval], bucket_secs: u64) -> Vec<(u64, u64)> {
+    let mut buckets: Vec<(u64, u64)> = Vec::new();
+    for &(mut start, end) in intervals {
+        while start < end {
+            let bucket = start / bucket_secs;
+            let next = end.min((bucket + 1) * bucket_secs);
+            match buckets.last_mut() {
+                Some((last, secs)) if *last == bucket => *secs += next - start,
+                _ => buckets.push((bucket, next - start)),
+            }
+            start = next;
+        }
+    }
+    buckets
+}
+
+pub fn subtract_playtime(
+    conn: &Connection,
+    bucket: u64,
+    object_id: u32,
+    user_id: u32,
+    source: u32,
+    secs: u64,
+) -> Result<()> {
+    conn.execute(
+        "update timeline set value = max(value - ?1, 0) \
+            where timestamp = ?2 and object_id = ?3 and user_id = ?4 and source = ?5",
+        (secs, bucket, object_id, user_id, source),
+    )?;
+    conn.execute(
+        "delete from timeline where value = 0 \
+            and timestamp = ?1 and object_id = ?2 and user_id = ?3 and source = ?4",
+        (bucket, object_id, user_id, source),
+    )?;
+    Ok(())
+}
+
+/// Sessions of `object_id` started before `to`, each as the intervals it was
+/// running and not suspended and whether it is still open, open sessions are
+/// considered running until `to`.
+fn sessions_of(conn: &Connection, object_id: u32, to: u64) -> Result<Vec<(Vec<Interval>, bool)>> {
+    let mut stmt = conn.prepare_cached(
+        "select timestamp, event_type from events \
+            where object_id = ?1 and timestamp < ?2 \
+            order by timestamp, rowid",
+    )?;
+    let mut sessions = Vec::new();
+    let mut session: Option<Vec<Interval>> = None;
+    let mut start = None;
+    let mut rows = stmt.query((object_id, to))?;
+    while let Some(row) = rows.next()? {
+        let timestamp: u64 = row.get(0)?;
+        let event_type: u32 = row.get(1)?;
+        match event_type {
+            t if t == EventType::Started as u32 => {
+                session.get_or_insert_with(Vec::new);
+                start = start.or(Some(timestamp));
+            }
+            t if t == EventType::Stopped as u32 => {
+                if let Some(mut intervals) = session.take() {
+                    intervals.extend(start.take().map(|start| (start, timestamp)));
+                    sessions.push((intervals, false));
+                }
+            }
+            t if t == EventType::Suspended as u32 => {
+                if let (Some(intervals), Some(start)) = (session.as_mut(), start.take()) {
+                    intervals.push((start, timestamp));
+                }
+            }
+            t if t == EventType::Resumed as u32 && session.is_some() => {
+                start = start.or(Some(timestamp));
+            }
+            _ => {}
+        }
+    }
+    if let Some(mut intervals) = session {
+        intervals.extend(start.map(|start| (start, to)));
+        sessions.push((intervals, true));
+    }
+    Ok(sessions)
+}
+
+/// Intervals between `from` and `to` during which `object_id` was running
+/// and not suspended.
+fn running_intervals(
+    conn: &Connection,
+    object_id: u32,
+    from: u64,
+    to: u64,
+) -> Result<Vec<Interval>> {
+    let intervals = sessions_of(conn, object_id, to)?
+        .into_iter()
+        .flat_map(|(intervals, _)| intervals)
+        .collect();
+    Ok(clip(intervals, from, to))
+}
+
+/// Spans from start to stop of the sessions of `object_id` between `from`
+/// and `to`, including the time they were suspended.
+fn session_spans(conn: &Connection, object_id: u32, from: u64, to: u64) -> Result<Vec<Interval>> {
+    let mut stmt = conn.prepare_cached(
+        "select timestamp, event_type from events \
+            where object_id = ?1 and event_type in (?2, ?3) and timestamp < ?4 \
+            order by timestamp, rowid",
+    )?;
+    let mut spans = Vec::new();
+    let mut start = None;
+    let mut rows = stmt.query((
+        object_id,
+        EventType::Started as u32,
+        EventType::Stopped as u32,
+        to,
+    ))?;
+    while let Some(row) = rows.next()? {
+        let timestamp: u64 = row.get(0)?;
+        let event_type: u32 = row.get(1)?;
+        if event_type == EventType::Started as u32 {
+            start = start.or(Some(timestamp));
+        } else if let Some(start) = start.take() {
+            spans.push((start, timestamp));
+        }
+    }
+    spans.extend(start.map(|start| (start, to)));
+    Ok(clip(spans, from, to))
+}
+
+/// Intervals between `from` and `to` during which tracking was paused.
+fn paused_intervals(
+    conn: &Connection,
+    this_object_id: u32,
+    from: u64,
+    to: u64,
+) -> Result<Vec<Interval>> {
+    let mut stmt = conn.prepare_cached(
+        "select timestamp, event_type from events \
+            where object_id = ?1 and event_type in (?2, ?3) and timestamp < ?4 \
+            order by timestamp, rowid",
+    )?;
+    let mut intervals = Vec::new();
+    let mut start = None;
+    let mut rows = stmt.query((
+        this_object_id,
+        EventType::TrackingPaused as u32,
+        EventType::TrackingResumed as u32,
+        to,
+    ))?;
+    while let Some(row) = rows.next()? {
+        let timestamp: u64 = row.get(0)?;
+        let event_type: u32 = row.get(1)?;
+        if event_type == EventType::TrackingPaused as u32 {
+            start = start.or(Some(timestamp));
+        } else {
+            intervals.extend(start.take().map(|start| (start, timestamp)));
+        }
+    }
+    intervals.extend(start.map(|start| (start, to)));
+    Ok(clip(intervals, from, to))
+}
+
+/// Subtracts the time played between `from` and `to` from `timeline` and
+/// records the window in `exclusions`, returns the number of seconds removed.
+///
+/// Time already removed by an earlier overlapping exclusion is not removed twice.
+pub fn exclude(conn: &Connection, from: u64, to: u64, app_id: Option<AppId>) -> Result<u64> {
+    let this_object_id = DeckDB::get_object_id(conn, THIS_APP_ID)?;
+    if let Some(app_id) = app_id {
+        DeckDB::get_object_id(conn, app_id)?;
+    }
+    let bucket_secs = DeckDB::bucket_secs(conn)?;
+    let paused = paused_intervals(conn, this_object_id, from, to)?;
+
+    let objects: Vec<(u32, AppId)> = conn
+        .prepare(
+            "select object_id, app_id from objects \
+                where object_id != ?1 and (?2 is null or app_id = ?2)",
+        )?
+        .query_map((this_object_id, app_id), |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<Result<_>>()?;
+
+    let mut removed = 0;
+    for (object_id, _) in objects.iter().copied() {
+        let excluded: Vec<Interval> = conn
+            .prepare_cached(
+                "select start_ts, end_ts from exclusions \
+                    where start_ts < ?1 and end_ts > ?2 \
+                    and (object_id is null or object_id = ?3)",
+            )?
+            .query_map((to, from, object_id), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_>>()?;
+
+        let intervals = running_intervals(conn, object_id, from, to)?;
+        let intervals = subtract(subtract(intervals, &paused), &excluded);
+
+        for (bucket, mut secs) in split_buckets(&intervals, bucket_secs) {
+            let rows: Vec<(u32, u32, u64)> = conn
+                .prepare_cached(
+                    "select user_id, source, value from timeline \
+                        where timestamp = ?1 and object_id = ?2 order by value desc",
+                )?
+                .query_map((bucket, object_id), |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })?
+                .collect::<Result<_>>()?;
+            for (user_id, source, value) in rows {
+                let cut = secs.min(value);
+                subtract_playtime(conn, bucket, object_id, user_id, source, cut)?;
+                secs -= cut;
+                removed += cut;
+            }
+        }
+    }
+
+    let object_id = match app_id {
+        Some(_) => objects.first().map(|&(object_id, _)| object_id),
+        None => None,
+    };
+    conn.execute(
+        "insert into exclusions (start_ts, end_ts, object_id, seconds) values (?1, ?2, ?3, ?4)",
+        (from, to, object_id, removed),
+    )?;
+
+    info!("excluded {removed}s of playtime between {from} and {to}");
+    Ok(removed)
+}
+
+/// Observed or manual session of an app.
+pub struct AppSession {
+    pub app_id: AppId,
+    pub start: u64,
+    pub end: u64,
+    /// Time played, without suspended, paused and excluded time.
+    pub secs: u64,
+    /// Not stopped yet, `end` is the time the sessions were read at.
+    pub running: bool,
+    /// Parts of the session between suspends and excluded windows.
+    pub parts: Vec<Interval>,
+    /// Time the session was started at, before any exclusion.
+    pub started: u64,
+    /// Id of a manually logged session.
+    pub manual_id: Option<u64>,
+}
+
+/// Sessions of all apps started before `now`, ordered by app and start.
+/// Excluded windows are cut out of the sessions, sessions entirely within
+/// them are left out.
+pub fn app_sessions(conn: &Connection, now: u64) -> Result<Vec<AppSession>> {
+    let this_object_id = DeckDB::get_object_id(conn, THIS_APP_ID)?;
+    let paused = paused_intervals(conn, this_object_id, 0, now)?;
+
+    let objects: Vec<(u32, AppId)> = conn
+        .prepare("select object_id, app_id from objects where object_id != ?1 order by app_id")?
+        .query_map((this_object_id,), |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_>>()?;
+
+    let mut app_sessions = Vec::new();
+    for (object_id, app_id) in objects {
+        let excluded: Vec<Interval> = conn
+            .prepare_cached(
+                "select start_ts, end_ts from exclusions \
+                    where object_id is null or object_id = ?1",
+            )?
+            .query_map((object_id,), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_>>()?;
+
+        for (intervals, running) in sessions_of(conn, object_id, now)? {
+            let Some(&(started, _)) = intervals.first() else {
+                continue;
+            };
+            let parts = subtract(intervals, &excluded);
+            let (Some(&(start, _)), Some(&(_, end))) = (parts.first(), parts.last()) else {
+                continue;
+            };
+            let secs = subtract(parts.clone(), &paused)
+                .iter()
+                .map(|(start, end)| end - start)
+                .sum();
+            let manual_id = conn
+                .prepare_cached(
+                    "select session_id from sessions where object_id = ?1 and start_ts = ?2",
+                )?
+                .query_row((object_id, started), |row| row.get(0))
+                .optional()?;
+            app_sessions.push(AppSession {
+                app_id,
+                start,
+                end,
+                secs,
+                running,
+                parts,
+                started,
+                manual_id,
+            });
+        }
+    }
+    Ok(app_sessions)
+}
+
+/// Manually entered session.
+pub struct Session {
+    pub id: u64,
+    pub app_id: AppId,
+    pub alias: Option<String>,
+    pub start: u64,
+    pub end: u64,
+}
+
+pub fn format_timestamp(timestamp: u64) -> String {
+    DateTime::<Local>::from(UNIX_EPOCH + Duration::from_secs(timestamp))
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+fn db_error(err: rusqlite::Error) -> String {
+    err.to_string()
+}
+
+/// Resolves an app id or a case-insensitive alias.
+pub fn resolve_app(conn: &Connection, app: &str) -> Result<AppId, String> {
+    if let Ok(app_id) = app.parse() {
+        return Ok(app_id);
+    }
+    let app_ids: Vec<AppId> = conn
+        .prepare("select app_id from objects where alias = ?1 collate nocase")
+        .and_then(|mut stmt| stmt.query_map((app,), |row| row.get(0))?.collect())
+        .map_err(db_error)?;
+    match app_ids[..] {
+        [app_id] => Ok(app_id),
+        [] => Err(format!("unknown app {app:?}")),
+        _ => Err(format!("alias {app:?} is ambiguous")),
+    }
+}
+
+/// Resolves a known Steam account by steam id, account or persona name.
+pub fn resolve_user(conn: &Connection, user: &str) -> Result<UserId, String> {
+    let user_ids: Vec<UserId> = conn
+        .prepare(
+            "select user_id from users where cast(steam_id as text) = ?1 \
+                or account_name = ?1 collate nocase or persona_name = ?1 collate nocase",
+        )
+        .and_then(|mut stmt| stmt.query_map((user,), |row| row.get(0))?.collect())
+        .map_err(db_error)?;
+    match user_ids[..] {
+        [user_id] => Ok(user_id),
+        [] => Err(format!("unknown user {user:?}")),
+        _ => Err(format!("user {user:?} is ambiguous")),
+    }
+}
+
+fn check(conn: &Connection, object_id: u32, start: u64, end: u64, now: u64) -> Result<(), String> {
+    if start >= end {
+        return Err("the session must end after it starts".to_owned());
+    }
+    if end > now {
+        return Err("the session must not end in the future".to_owned());
+    }
+    match session_spans(conn, object_id, start, end)
+        .map_err(db_error)?
+        .first()
+    {
+        Some(&(start, end)) => Err(format!(
+            "the app was already running between {} and {}",
+            format_timestamp(start),
+            format_timestamp(end)
+        )),
+        None => Ok(()),
+    }
+}
+
+fn insert(
+    conn: &Connection,
+    id: Option<u64>,
+    object_id: u32,
+    user_id: UserId,
+    start: u64,
+    end: u64,
+) -> Result<u64> {
+    let id = conn.query_row(
+        "insert into sessions (session_id, object_id, user_id, start_ts, end_ts) \
+            values (?1, ?2, ?3, ?4, ?5) returning session_id",
+        (id, object_id, user_id, start, end),
+        |row| row.get(0),
+    )?;
+    for (timestamp, event_type) in [(start, EventType::Started), (end, EventType::Stopped)] {
+        conn.execute(
+            "insert into events (timestamp, object_id, event_type, user_id, source) \
+                values (?1, ?2, ?3, ?4, ?5)",
+            (
+                timestamp,
+                object_id,
+                event_type as u32,
+                user_id,
+                Source::Manual as u32,
+            ),
+        )?;
+    }
+    let bucket_secs = DeckDB::bucket_secs(conn)?;
+    for (bucket, secs) in split_buckets(&[(start, end)], bucket_secs) {
+        conn.execute(
+            "insert into timeline (timestamp, object_id, user_id, source, value, utc_offset) \
+                values (?1, ?2, ?3, ?4, ?5, ?6) \
+                on conflict do update set value = value + excluded.value",
+            (
+                bucket,
+                object_id,
+                user_id,
+                Source::Manual as u32,
+                secs,
+                db::local_offset(bucket * bucket_secs),
+            ),
+        )?;
+    }
+    Ok(id)
+}
+
+/// Removes a manual session with its events and playtime, returns its
+/// object id, user id, start and end.
+fn remove(conn: &Connection, id: u64) -> Result<Option<(u32, UserId, u64, u64)>> {
+    let session: Option<(u32, UserId, u64, u64)> = conn
+        .query_row(
+            "delete from sessions where session_id = ?1 \
+                returning object_id, user_id, start_ts, end_ts",
+            (id,),
+            |row| Ok(Some((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))),
+        )
+        .or_else(|err| match err {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            err => Err(err),
+        })?;
+    let Some((object_id, user_id, start, end)) = session else {
+        return Ok(None);
+    };
+    for (timestamp, event_type) in [(start, EventType::Started), (end, EventType::Stopped)] {
+        conn.execute(
+            "delete from events where rowid in ( \
+                select rowid from events \
+                where timestamp = ?1 and object_id = ?2 and event_type = ?3 and source = ?4 \
+                limit 1)",
+            (
+                timestamp,
+                object_id,
+                event_type as u32,
+                Source::Manual as u32,
+            ),
+        )?;
+    }
+    let bucket_secs = DeckDB::bucket_secs(conn)?;
+    for (bucket, secs) in split_buckets(&[(start, end)], bucket_secs) {
+        subtract_playtime(
+            conn,
+            bucket,
+            object_id,
+            user_id,
+            Source::Manual as u32,
+            secs,
+        )?;
+    }
+    Ok(session)
+}
+
+/// Records a session played outside of the device, returns its id.
+pub fn add_session(
+    conn: &Connection,
+    app_id: AppId,
+    user_id: UserId,
+    start: u64,
+    end: u64,
+    now: u64,
+) -> Result<u64, String> {
+    let object_id = DeckDB::get_object_id(conn, app_id).map_err(db_error)?;
+    check(conn, object_id, start, end, now)?;
+    let id = insert(conn, None, object_id, user_id, start, end).map_err(db_error)?;
+    info!("added session #{id} of app_id={app_id} between {start} and {end}");
+    Ok(id)
+}
+
+/// Changes the app, start or end of a manual session.
+pub fn edit_session(
+    conn: &Connection,
+    id: u64,
+    app_id: Option<AppId>,
+    start: Option<u64>,
+    end: Option<u64>,
+    now: u64,
+) -> Result<(), String> {
+    let (old_object_id, user_id, old_start, old_end) = remove(conn, id)
+        .map_err(db_error)?
+        .ok_or_else(|| format!("unknown session #{id}"))?;
+    let object_id = match app_id {
+        Some(app_id) => DeckDB::get_object_id(conn, app_id).map_err(db_error)?,
+        None => old_object_id,
+    };
+    let (start, end) = (start.unwrap_or(old_start), end.unwrap_or(old_end));
+    if let Err(err) = check(conn, object_id, start, end, now) {
+        insert(conn, Some(id), old_object_id, user_id, old_start, old_end).map_err(db_error)?;
+        return Err(err);
+    }
+    insert(conn, Some(id), object_id, user_id, start, end).map_err(db_error)?;
+    info!("changed session #{id} to be between {start} and {end}");
+    Ok(())
+}
+
+pub fn delete_session(conn: &Connection, id: u64) -> Result<(), String> {
+    remove(conn, id)
+        .map_err(db_error)?
+        .ok_or_else(|| format!("unknown session #{id}"))?;
+    info!("deleted session #{id}");
+    Ok(())
+}
+
+pub fn list_sessions(conn: &Connection) -> Result<Vec<Session>> {
+    conn.prepare(
+        "select session_id, app_id, alias, start_ts, end_ts from sessions \
+            join objects on sessions.object_id = objects.object_id \
+            order by start_ts",
+    )?
+    .query_map((), |row| {
+        Ok(Session {
+            id: row.get(0)?,
+            app_id: row.get(1)?,
+            alias: row.get(2)?,
+            start: row.get(3)?,
+            end: row.get(4)?,
+        })
+    })?
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intervals() {
+        assert_eq!(
+            subtract(vec![(0, 100), (200, 300)], &[(50, 250), (280, 290)]),
+            vec![(0, 50), (250, 280), (290, 300)]
+        );
+        assert_eq!(
+            clip(vec![(0, 100), (150, 200)], 50, 160),
+            vec![(50, 100), (150, 160)]
+        );
+        assert_eq!(
+            parse_timestamp("1700000000"),
+            Ok(UNIX_EPOCH + Duration::from_secs(1700000000))
+        );
+        assert_eq!(
+            parse_timestamp("2023-11-14T22:13:20Z"),
+            Ok(UNIX_EPOCH + Duration::from_secs(1700000000))
+        );
+        assert!(parse_timestamp("2023-11-14 22:13").is_ok());
+        assert!(parse_timestamp("yesterday").is_err());
+    }
+
+    #[test]
+    fn exclude_window() {
+        let time = |n| UNIX_EPOCH + Duration::from_secs(n);
+        let mut db = DeckDB::build(":memory:", time(3600), None).unwrap();
+        db.event(time(3700), Some(7), EventType::Started).unwrap();
+        db.update(7, time(7200), 3500);
+        db.commit(time(7200)).unwrap();
+        db.update(7, time(8200), 1000);
+        db.set_paused(time(7500), true).unwrap();
+        db.set_paused(time(7600), false).unwrap();
+        db.flush(time(8200)).unwrap();
+
+        assert_eq!(db.exclude(7000, 7400, Some(7)).unwrap(), 400);
+        assert_eq!(db.exclude(6900, 7400, None).unwrap(), 100);
+        assert_eq!(db.exclude(7450, 7650, Some(7)).unwrap(), 100);
+        assert_eq!(db.exclude(9000, 9900, Some(7)).unwrap(), 0);
+
+        assert_eq!(db.playtime(Some(7), time(0)).unwrap(), 3900);
+        assert_eq!(db.playtime(Some(7), time(7200)).unwrap(), 700);
+    }
+
+    #[test]
+    fn excluded_sessions() {
+        let conn = DeckDB::open(":memory:").unwrap();
+        add_session(&conn, 5, db::UNKNOWN_USER_ID, 3600, 7200, 10000).unwrap();
+        add_session(&conn, 5, db::UNKNOWN_USER_ID, 8000, 8600, 10000).unwrap();
+        exclude(&conn, 4000, 4600, None).unwrap();
+        exclude(&conn, 7900, 9000, Some(5)).unwrap();
+
+        let sessions = app_sessions(&conn, 10000).unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].parts, vec![(3600, 4000), (4600, 7200)]);
+        assert_eq!(
+            (sessions[0].start, sessions[0].end, sessions[0].secs),
+            (3600, 7200, 3000)
+        );
+    }
+
+    #[test]
+    fn manual_sessions() {
+        let conn = DeckDB::open(":memory:").unwrap();
+        let timeline = || {
+            conn.prepare("select timestamp, value from timeline where source = 1 order by 1")
+                .unwrap()
+                .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+                .unwrap()
+                .collect::<Result<Vec<(u64, u64)>>>()
+                .unwrap()
+        };
+        let events = || {
+            conn.query_row("select count(*) from events where source = 1", (), |row| {
+                row.get::<_, u64>(0)
+            })
+            .unwrap()
+        };
+
+        let id = add_session(&conn, 5, db::UNKNOWN_USER_ID, 3000, 5000, 10000).unwrap();
+        assert_eq!(timeline(), vec![(0, 600), (1, 1400)]);
+        assert!(add_session(&conn, 5, db::UNKNOWN_USER_ID, 4900, 5100, 10000).is_err());
+        assert!(add_session(&conn, 5, db::UNKNOWN_USER_ID, 6000, 11000, 10000).is_err());
+        assert!(edit_session(&conn, id, None, None, Some(2000), 10000).is_err());
+
+        // Observed sessions include the time they were suspended.
+        let object_id = DeckDB::get_object_id(&conn, 6).unwrap();
+        for (timestamp, event_type) in [
+            (1000, EventType::Started),
+            (1500, EventType::Suspended),
+            (2500, EventType::Resumed),
+            (3000, EventType::Stopped),
+        ] {
+            conn.execute(
+                "insert into events (timestamp, object_id, event_type) values (?1, ?2, ?3)",
+                (timestamp, object_id, event_type as u32),
+            )
+            .unwrap();
+        }
+        assert!(add_session(&conn, 6, db::UNKNOWN_USER_ID, 1600, 2400, 10000).is_err());
+
+        conn.execute("update objects set alias = 'Hades' where app_id = 5", ())
+            .unwrap();
+        assert_eq!(resolve_app(&conn, "hades"), Ok(5));
+        assert!(resolve_app(&conn, "Celeste").is_err());
+
+        edit_session(&conn, id, None, Some(3600), Some(4000), 10000).unwrap();
+        assert_eq!(timeline(), vec![(1, 400)]);
+        assert_eq!(events(), 2);
+        assert_eq!(list_sessions(&conn).unwrap()[0].start, 3600);
+
+        delete_session(&conn, id).unwrap();
+        assert!(delete_session(&conn, id).is_err());
+        assert_eq!(timeline(), vec![]);
+        assert_eq!(events(), 0);
+
+        // Sessions count for the given or else the active user.
+        conn.execute(
+            "insert into users (user_id, steam_id, account_name, persona_name) \
+                values (1, 76561, 'deck', 'Deck'), (2, 76562, 'guest', null)",
+            (),
+        )
+        .unwrap();
+        let users = || {
+            conn.prepare(
+                "select distinct user_id from timeline where source = 1 \
+                    union select user_id from events where source = 1",
+            )
+            .unwrap()
+            .query_map((), |row| row.get(0))
+            .unwrap()
+            .collect::<Result<Vec<UserId>>>()
+            .unwrap()
+        };
+        let session = |user: Option<&str>| NewSession {
+            app: "hades".to_owned(),
+            start: 3600,
+            end: 4000,
+            user: user.map(str::to_owned),
+        };
+        assert!(session(Some("nobody")).add(&conn, 2, 10000).is_err());
+        let id = session(Some("Deck")).add(&conn, 2, 10000).unwrap();
+        edit_session(&conn, id, None, Some(3700), None, 10000).unwrap();
+        assert_eq!(users(), vec![1]);
+        delete_session(&conn, id).unwrap();
+        session(None).add(&conn, 2, 10000).unwrap();
+        assert_eq!(users(), vec![2]);
+    }
+}
@@ -0,0 +1,307 @@
+use crate::{
+    db::{AppId, DeckDB, THIS_APP_ID},
+    report, sessions,
+};
+use chrono::{DateTime, Datelike, NaiveDate, Weekday};
+use rusqlite::{Connection, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Serialize)]
+pub struct AppStats {
+    pub app_id: AppId,
+    pub alias: Option<String>,
+    pub total_secs: u64,
+    pub sessions: u64,
+    pub longest_session_secs: u64,
+    pub average_session_secs: u64,
+    pub first_played: Option<NaiveDate>,
+    pub last_played: Option<NaiveDate>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Streak {
+    pub days: u64,
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+}
+
+/// App that was not played for a while.
+#[derive(Serialize)]
+pub struct Forgotten {
+    pub app_id: AppId,
+    pub alias: Option<String>,
+    pub days: u64,
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+    pub apps: Vec<AppStats>,
+    pub longest_streak: Option<Streak>,
+    pub most_played_weekday: Option<Weekday>,
+    pub most_played_hour: Option<u32>,
+    /// Playtime per local weekday, starting on Monday.
+    pub weekday_secs: Vec<u64>,
+    /// Playtime per local hour of the day.
+    pub hour_secs: Vec<u64>,
+    pub forgotten: Vec<Forgotten>,
+}
+
+fn local_date(timestamp: u64, utc_offset: i32) -> NaiveDate {
+    DateTime::from_timestamp(timestamp as i64 + utc_offset as i64, 0)
+        .unwrap_or_default()
+        .date_naive()
+}
+
+fn longest_streak(days: &[(NaiveDate, u64)]) -> Option<Streak> {
+    let mut longest: Option<Streak> = None;
+    let mut current: Option<Streak> = None;
+    for &(date, _) in days.iter().filter(|(_, secs)| *secs > 0) {
+        let streak = match current {
+            Some(streak) if streak.end.succ_opt() == Some(date) => Streak {
+                days: streak.days + 1,
+                end: date,
+                ..streak
+            },
+            _ => Streak {
+                days: 1,
+                start: date,
+                end: date,
+            },
+        };
+        if longest.is_none_or(|longest| streak.days > longest.days) {
+            longest = Some(streak);
+        }
+        current = Some(streak);
+    }
+    longest
+}
+
+fn argmax(values: &[u64]) -> Option<usize> {
+    values
+        .iter()
+        .enumerate()
+        .filter(|(_, &value)| value > 0)
+        .max_by_key(|&(i, &value)| (value, usize::MAX - i))
+        .map(|(i, _)| i)
+}
+
+/// Derives statistics from sessions and `timeline`, `utc_offset` gives the
+/// local offset for times without a stored one.
+pub fn compute(
+    conn: &Connection,
+    now: u64,
+    forgotten_days: u64,
+    utc_offset: impl Fn(u64) -> i32,
+) -> Result<Stats> {
+    let totals: HashMap<AppId, u64> = report::app_totals(conn, None)?
+        .into_iter()
+        .map(|total| (total.app_id, total.seconds))
+        .collect();
+
+    let mut by_app: HashMap<AppId, Vec<sessions::AppSession>> = HashMap::new();
+    for session in sessions::app_sessions(conn, now)? {
+        by_app.entry(session.app_id).or_default().push(session);
+    }
+
+    let objects: Vec<(AppId, Option<String>)> = conn
+        .prepare("select app_id, alias from objects where app_id != ?1 order by app_id")?
+        .query_map((THIS_APP_ID,), |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_>>()?;
+
+    let mut apps = Vec::new();
+    let mut forgotten = Vec::new();
+    for (app_id, alias) in objects {
+        let sessions: Vec<_> = by_app
+            .remove(&app_id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|session| session.secs > 0)
+            .collect();
+        let total_secs = totals.get(&app_id).copied().unwrap_or(0);
+        if sessions.is_empty() && total_secs == 0 {
+            continue;
+        }
+
+        let first = sessions.iter().map(|session| session.start).min();
+        let last = sessions.iter().map(|session| session.end).max();
+        if let Some(last) = last.filter(|&last| now.saturating_sub(last) > forgotten_days * 86400) {
+            forgotten.push(Forgotten {
+                app_id,
+                alias: alias.clone(),
+                days: (now - last) / 86400,
+            });
+        }
+
+        let secs: Vec<u64> = sessions.iter().map(|session| session.secs).collect();
+        apps.push(AppStats {
+            app_id,
+            alias,
+            total_secs,
+            sessions: secs.len() as u64,
+            longest_session_secs: secs.iter().copied().max().unwrap_or(0),
+            average_session_secs: secs.iter().sum::<u64>() / (secs.len() as u64).max(1),
+            first_played: first.map(|ts| local_date(ts, utc_offset(ts))),
+            last_played: last.map(|ts| local_date(ts, utc_offset(ts))),
+        });
+    }
+    apps.sort_by_key(|app| std::cmp::Reverse(app.total_secs));
+    forgotten.sort_by_key(|app| std::cmp::Reverse(app.days));
+
+    let days = report::daily_totals(conn, None, &utc_offset)?;
+    let mut weekday_secs = vec![0; 7];
+    for (date, secs) in days.iter() {
+        weekday_secs[date.weekday().num_days_from_monday() as usize] += secs;
+    }
+
+    let bucket_secs = DeckDB::bucket_secs(conn)?;
+    let mut hour_secs = vec![0; 24];
+    let mut stmt = conn.prepare(
+        "select timestamp, utc_offset, sum(value) from timeline group by timestamp, utc_offset",
+    )?;
+    let mut rows = stmt.query(())?;
+    while let Some(row) = rows.next()? {
+        let start = row.get::<_, u64>(0)? * bucket_secs;
+        let offset = row
+            .get::<_, Option<i32>>(1)?
+            .unwrap_or_else(|| utc_offset(start));
+        let value = row.get(2)?;
+        for (hour, secs) in report::split_hours(start as i64, bucket_secs as i64, offset, value) {
+            hour_secs[hour.rem_euclid(24) as usize] += secs;
+        }
+    }
+
+    Ok(Stats {
+        apps,
+        longest_streak: longest_streak(&days),
+        most_played_weekday: argmax(&weekday_secs)
+            .and_then(|day| Weekday::try_from(day as u8).ok()),
+        most_played_hour: argmax(&hour_secs).map(|hour| hour as u32),
+        weekday_secs,
+        hour_secs,
+        forgotten,
+    })
+}
+
+pub fn print_stats(stats: &Stats) {
+    let date = |date: Option<NaiveDate>| date.map_or("-".to_owned(), |date| date.to_string());
+    println!(
+        "{:>10}  {:<24}  {:>10}  {:>8}  {:>10}  {:>10}  {:<10}  {:<10}",
+        "app_id", "alias", "playtime", "sessions", "longest", "average", "first", "last"
+    );
+    for app in stats.apps.iter() {
+        println!(
+            "{:>10}  {:<24}  {:>10}  {:>8}  {:>10}  {:>10}  {:<10}  {:<10}",
+            app.app_id,
+            app.alias.as_deref().unwrap_or("-"),
+            report::format_duration(app.total_secs),
+            app.sessions,
+            report::format_duration(app.longest_session_secs),
+            report::format_duration(app.average_session_secs),
+            date(app.first_played),
+            date(app.last_played),
+        );
+    }
+
+    println!();
+    match &stats.longest_streak {
+        Some(streak) => println!(
+            "longest streak:       {} days ({} - {})",
+            streak.days, streak.start, streak.end
+        ),
+        None => println!("longest streak:       -"),
+    }
+    match stats.most_played_weekday {
+        Some(weekday) => println!("most played weekday:  {weekday}"),
+        None => println!("most played weekday:  -"),
+    }
+    match stats.most_played_hour {
+        Some(hour) => println!("most played hour:     {hour:02}:00"),
+        None => println!("most played hour:     -"),
+    }
+
+    if !stats.forgotten.is_empty() {
+        println!();
+        println!("forgotten games:");
+        for app in stats.forgotten.iter() {
+            println!(
+                "{:>10}  {:<24}  {} days ago",
+                app.app_id,
+                app.alias.as_deref().unwrap_or("-"),
+                app.days
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{EventType, UNKNOWN_USER_ID};
+
+    #[test]
+    fn derived() {
+        let conn = DeckDB::open(":memory:").unwrap();
+        let ts = |m, d, h, min| {
+            NaiveDate::from_ymd_opt(if m == 12 { 2023 } else { 2024 }, m, d)
+                .unwrap()
+                .and_hms_opt(h, min, 0)
+                .unwrap()
+                .and_utc()
+                .timestamp() as u64
+        };
+        let now = ts(1, 4, 0, 0);
+        for (app_id, start, end) in [
+            (10, ts(1, 1, 10, 0), ts(1, 1, 11, 0)),
+            (10, ts(1, 2, 10, 0), ts(1, 2, 10, 30)),
+            (10, ts(1, 3, 20, 0), ts(1, 3, 22, 0)),
+            (20, ts(12, 1, 10, 0), ts(12, 1, 10, 10)),
+        ] {
+            sessions::add_session(&conn, app_id, UNKNOWN_USER_ID, start, end, now).unwrap();
+        }
+        for (timestamp, event_type) in [
+            (ts(1, 3, 21, 0), EventType::Suspended),
+            (ts(1, 3, 21, 30), EventType::Resumed),
+        ] {
+            conn.execute(
+                "insert into events (timestamp, object_id, event_type) \
+                    select ?1, object_id, ?2 from objects where app_id = 10",
+                (timestamp, event_type as u32),
+            )
+            .unwrap();
+        }
+        conn.execute("update timeline set utc_offset = 0", ())
+            .unwrap();
+
+        let stats = compute(&conn, now, 30, |_| 0).unwrap();
+        let hades = &stats.apps[0];
+        assert_eq!(
+            (hades.app_id, hades.total_secs, hades.sessions),
+            (10, 12600, 3)
+        );
+        assert_eq!(
+            (hades.longest_session_secs, hades.average_session_secs),
+            (5400, 3600)
+        );
+        assert_eq!(hades.first_played, NaiveDate::from_ymd_opt(2024, 1, 1));
+        assert_eq!(hades.last_played, NaiveDate::from_ymd_opt(2024, 1, 3));
+        assert_eq!(
+            stats.longest_streak,
+            Some(Streak {
+                days: 3,
+                start: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+                end: NaiveDate::from_ymd_opt(2024, 1, 3).unwrap(),
+            })
+        );
+        assert_eq!(stats.most_played_weekday, Some(Weekday::Wed));
+        assert_eq!(stats.most_played_hour, Some(10));
+        assert_eq!(
+            stats
+                .forgotten
+                .iter()
+                .map(|app| (app.app_id, app.days))
+                .collect::<Vec<_>>(),
+            vec![(20, 33)]
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use log::debug;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Value of a text KeyValues (VDF) file.
+#[derive(Debug, PartialEq)]
+pub enum Vdf {
+    Str(String),
+    Map(Vec<(String, Vdf)>),
+}
+
+impl Vdf {
+    /// Looks up a key case-insensitively, as Steam itself does.
+    pub fn get(&self, key: &str) -> Option<&Vdf> {
+        match self {
+            Vdf::Map(entries) => entries
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(key))
+                .map(|(_, v)| v),
+            Vdf::Str(_) => None,
+        }
+    }
+
+    pub fn path(&self, keys: &[&str]) -> Option<&Vdf> {
+        keys.iter().try_fold(self, |node, key| node.get(key))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Vdf::Str(s) => Some(s),
+            Vdf::Map(_) => None,
+        }
+    }
+
+    pub fn entries(&self) -> &[(String, Vdf)] {
+        match self {
+            Vdf::Map(entries) => entries,
+            Vdf::Str(_) => &[],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Str(String),
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => tokens.push(Token::Open),
+            '}' => tokens.push(Token::Close),
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '\\' => match chars.next()? {
+                            'n' => s.push('\n'),
+                            't' => s.push('\t'),
+                            c => s.push(c),
+                        },
+                        c => s.push(c),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                chars.find(|&c| c == '\n');
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut s = String::from(c);
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '{' || c == '}' || c == '"' {
+                        break;
+                    }
+                    s.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Str(s));
+            }
+        }
+    }
+    Some(tokens)
+}
+
+fn parse_map(tokens: &mut impl Iterator<Item = Token>, nested: bool) -> Option<Vdf> {
+    let mut entries = Vec::new();
+    loop {
+        let key = match tokens.next() {
+            Some(Token::Str(key)) => key,
+            Some(Token::Close) if nested => break,
+            None if !nested => break,
+            _ => return None,
+        };
+        let value = match tokens.next()? {
+            Token::Str(value) => Vdf::Str(value),
+            Token::Open => parse_map(tokens, true)?,
+            Token::Close => return None,
+        };
+        entries.push((key, value));
+    }
+    Some(Vdf::Map(entries))
+}
+
+pub fn parse_vdf(text: &str) -> Option<Vdf> {
+    parse_map(&mut tokenize(text)?.into_iter(), false)
+}
+
+fn read_vdf(path: &Path) -> Option<Vdf> {
+    let text = fs::read_to_string(path).ok()?;
+    let vdf = parse_vdf(&text);
+    if vdf.is_none() {
+        debug!("unable to parse {path:?}");
+    }
+    vdf
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SteamUser {
+    pub steam_id: u64,
+    pub account_name: String,
+    pub persona_name: Option<String>,
+}
+
+/// Locations of the Steam files inside a home directory.
+pub struct SteamHome {
+    root: PathBuf,
+    registry: PathBuf,
+}
+
+impl SteamHome {
+    /// Prefers a native install over the Flatpak one, which keeps its files
+    /// in a separate home below `.var/app`.
+    pub fn from_home(home: &Path) -> SteamHome {
+        let flatpak = home.join(".var/app/com.valvesoftware.Steam");
+        let (base, root) = [home, &flatpak]
+            .into_iter()
+            .flat_map(|base| {
+                [".local/share/Steam", ".steam/steam"].map(|dir| (base, base.join(dir)))
+            })
+            .find(|(_, dir)| dir.is_dir())
+            .unwrap_or_else(|| (home, home.join(".local/share/Steam")));
+        SteamHome {
+            root,
+            registry: base.join(".steam/registry.vdf"),
+        }
+    }
+
+    pub fn from_env() -> Option<SteamHome> {
+        Some(SteamHome::from_home(Path::new(&std::env::var_os("HOME")?)))
+    }
+
+    fn loginusers(&self) -> PathBuf {
+        self.root.join("config/loginusers.vdf")
+    }
+
+    /// Modification time of the files the active user is read from.
+    pub fn mtime(&self) -> Option<SystemTime> {
+        [self.loginusers(), self.registry.clone()]
+            .iter()
+            .filter_map(|path| fs::metadata(path).ok()?.modified().ok())
+            .max()
+    }
+
+    /// Detects the logged in account, preferring the `MostRecent` entry of
+    /// `loginusers.vdf` and falling back to `AutoLoginUser` from the registry.
+    pub fn active_user(&self) -> Option<SteamUser> {
+        let loginusers = read_vdf(&self.loginusers())?;
+        let users: Vec<SteamUser> = loginusers
+            .get("users")?
+            .entries()
+            .iter()
+            .filter_map(|(steam_id, user)| {
+                Some(SteamUser {
+                    steam_id: steam_id.parse().ok()?,
+                    account_name: user.get("AccountName")?.as_str()?.to_owned(),
+                    persona_name: user
+                        .get("PersonaName")
+                        .and_then(Vdf::as_str)
+                        .map(str::to_owned),
+                })
+            })
+            .collect();
+
+        let most_recent = loginusers
+            .get("users")?
+            .entries()
+            .iter()
+            .find(|(_, user)| user.get("MostRecent").and_then(Vdf::as_str) == Some("1"))
+            .and_then(|(steam_id, _)| steam_id.parse::<u64>().ok());
+        if let Some(user) = users.iter().find(|user| Some(user.steam_id) == most_recent) {
+            return Some(user.clone());
+        }
+
+        let auto_login = read_vdf(&self.registry)?;
+        let account_name = auto_login
+            .path(&[
+                "Registry",
+                "HKCU",
+                "Software",
+                "Valve",
+                "Steam",
+                "AutoLoginUser",
+            ])?
+            .as_str()?;
+        users
+            .into_iter()
+            .find(|user| user.account_name.eq_ignore_ascii_case(account_name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> SteamHome {
+        SteamHome::from_home(
+            &Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures")
+                .join(name),
+        )
+    }
+
+    #[test]
+    fn parse() {
+        let vdf =
+            parse_vdf("\"a\" { // comment\n \"B\" \"x\\\"y\" c { d e } }\n\"f\" \"\"").unwrap();
+        assert_eq!(vdf.path(&["A", "b"]).unwrap().as_str(), Some("x\"y"));
+        assert_eq!(vdf.path(&["a", "c", "d"]).unwrap().as_str(), Some("e"));
+        assert_eq!(vdf.get("f").unwrap().as_str(), Some(""));
+        assert_eq!(parse_vdf("\"a\" { \"b\""), None);
+        assert_eq!(parse_vdf("}"), None);
+    }
+
+    #[test]
+    fn active_user() {
+        let user = fixture("steam-most-recent").active_user().unwrap();
+        assert_eq!(user.steam_id, 76561198000000002);
+        assert_eq!(user.account_name, "kid_account");
+        assert_eq!(user.persona_name.as_deref(), Some("Kid"));
+
+        let user = fixture("steam-auto-login").active_user().unwrap();
+        assert_eq!(user.steam_id, 76561198000000001);
+        assert_eq!(user.account_name, "parent_account");
+
+        let user = fixture("steam-flatpak").active_user().unwrap();
+        assert_eq!(user.steam_id, 76561198000000001);
+
+        assert_eq!(fixture("steam-missing").active_user(), None);
+    }
+}
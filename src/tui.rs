@@ -0,0 +1,361 @@
+use crate::{
+    control,
+    db::{self, AppId, DeckDB},
+    report, sessions,
+};
+use chrono::{DateTime, Datelike};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, Paragraph, Row, Sparkline, Table, TableState},
+    DefaultTerminal, Frame,
+};
+use rusqlite::{Connection, Result};
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub struct RunningApp {
+    pub app_id: AppId,
+    pub alias: Option<String>,
+    pub session_secs: u64,
+}
+
+pub struct SessionRow {
+    pub app_id: AppId,
+    pub alias: Option<String>,
+    pub start: u64,
+    pub secs: u64,
+    pub running: bool,
+}
+
+/// Everything shown on the dashboard, read on every refresh.
+pub struct Dashboard {
+    /// Whether running apps come from the daemon or from the database.
+    pub live: bool,
+    pub running: Vec<RunningApp>,
+    pub today_secs: u64,
+    pub week_secs: u64,
+    /// Playtime of each of the last 24 hours, oldest first.
+    pub hourly: Vec<u64>,
+    /// Sessions, newest first.
+    pub sessions: Vec<SessionRow>,
+}
+
+fn running_from_daemon(socket: &Path) -> Option<Vec<RunningApp>> {
+    let response = control::send(socket, &control::Request::Status).ok()?;
+    let running = response["result"]["running"].as_array()?;
+    Some(
+        running
+            .iter()
+            .map(|app| RunningApp {
+                app_id: app["app_id"].as_u64().unwrap_or(0) as AppId,
+                alias: app["alias"].as_str().map(str::to_owned),
+                session_secs: app["session_secs"].as_u64().unwrap_or(0),
+            })
+            .collect(),
+    )
+}
+
+pub fn load(
+    conn: &Connection,
+    socket: Option<&Path>,
+    now: u64,
+    utc_offset: impl Fn(u64) -> i32,
+) -> Result<Dashboard> {
+    let aliases: HashMap<AppId, String> = conn
+        .prepare("select app_id, alias from objects where alias is not null")?
+        .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_>>()?;
+    let alias = |app_id| aliases.get(&app_id).cloned();
+
+    let mut app_sessions = sessions::app_sessions(conn, now)?;
+    app_sessions.sort_by_key(|session| std::cmp::Reverse(session.start));
+
+    let daemon = socket.and_then(running_from_daemon);
+    let live = daemon.is_some();
+    let running = daemon.unwrap_or_else(|| {
+        app_sessions
+            .iter()
+            .filter(|session| session.running)
+            .map(|session| RunningApp {
+                app_id: session.app_id,
+                alias: alias(session.app_id),
+                session_secs: now - session.start,
+            })
+            .collect()
+    });
+
+    let today = DateTime::from_timestamp((now as i64) + utc_offset(now) as i64, 0)
+        .unwrap_or_default()
+        .date_naive();
+    let monday = today - chrono::Days::new(today.weekday().num_days_from_monday() as u64);
+    let days = report::daily_totals(conn, None, &utc_offset)?;
+    let today_secs = days
+        .iter()
+        .filter(|(date, _)| *date == today)
+        .map(|(_, secs)| secs)
+        .sum();
+    let week_secs = days
+        .iter()
+        .filter(|(date, _)| *date >= monday)
+        .map(|(_, secs)| secs)
+        .sum();
+
+    let bucket_secs = DeckDB::bucket_secs(conn)?;
+    let first_hour = (now as i64 + utc_offset(now) as i64).div_euclid(3600) - 23;
+    let mut hourly = vec![0; 24];
+    let mut stmt = conn.prepare(
+        "select timestamp, utc_offset, sum(value) from timeline where timestamp >= ?1 \
+            group by timestamp, utc_offset",
+    )?;
+    let mut rows = stmt.query((now.saturating_sub(2 * 86400) / bucket_secs,))?;
+    while let Some(row) = rows.next()? {
+        let start = row.get::<_, u64>(0)? * bucket_secs;
+        let offset = row
+            .get::<_, Option<i32>>(1)?
+            .unwrap_or_else(|| utc_offset(start));
+        let value = row.get(2)?;
+        for (hour, secs) in report::split_hours(start as i64, bucket_secs as i64, offset, value) {
+            if let Some(total) = usize::try_from(hour - first_hour)
+                .ok()
+                .and_then(|i| hourly.get_mut(i))
+            {
+                *total += secs;
+            }
+        }
+    }
+
+    let sessions = app_sessions
+        .into_iter()
+        .map(|session| SessionRow {
+            app_id: session.app_id,
+            alias: alias(session.app_id),
+            start: session.start,
+            secs: session.secs,
+            running: session.running,
+        })
+        .collect();
+
+    Ok(Dashboard {
+        live,
+        running,
+        today_secs,
+        week_secs,
+        hourly,
+        sessions,
+    })
+}
+
+fn draw(frame: &mut Frame, dashboard: &Dashboard, state: &mut TableState) {
+    let [running_area, summary_area, sessions_area, help_area] = Layout::vertical([
+        Constraint::Length(dashboard.running.len().max(1) as u16 + 2),
+        Constraint::Length(5),
+        Constraint::Min(3),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let running: Vec<Line> = match dashboard.running.is_empty() {
+        true => vec![Line::from("nothing is running")],
+        false => dashboard
+            .running
+            .iter()
+            .map(|app| {
+                Line::from(format!(
+                    "{:>10}  {:<32}  {}",
+                    app.app_id,
+                    app.alias.as_deref().unwrap_or("-"),
+                    report::format_duration(app.session_secs)
+                ))
+            })
+            .collect(),
+    };
+    let title = match dashboard.live {
+        true => " Running ",
+        false => " Running (from database) ",
+    };
+    frame.render_widget(
+        Paragraph::new(running).block(Block::bordered().title(title)),
+        running_area,
+    );
+
+    let [totals_area, sparkline_area] =
+        Layout::horizontal([Constraint::Length(24), Constraint::Min(24)]).areas(summary_area);
+    frame.render_widget(
+        Paragraph::new(vec![
+            Line::from(format!(
+                "today  {:>10}",
+                report::format_duration(dashboard.today_secs)
+            )),
+            Line::from(format!(
+                "week   {:>10}",
+                report::format_duration(dashboard.week_secs)
+            )),
+        ])
+        .block(Block::bordered().title(" Playtime ")),
+        totals_area,
+    );
+    frame.render_widget(
+        Sparkline::default()
+            .block(Block::bordered().title(" Last 24 hours "))
+            .data(&dashboard.hourly)
+            .max(3600),
+        sparkline_area,
+    );
+
+    let rows = dashboard.sessions.iter().map(|session| {
+        Row::new([
+            sessions::format_timestamp(session.start),
+            session.app_id.to_string(),
+            session.alias.clone().unwrap_or("-".to_owned()),
+            report::format_duration(session.secs),
+            if session.running { "running" } else { "" }.to_owned(),
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(19),
+            Constraint::Length(10),
+            Constraint::Min(16),
+            Constraint::Length(10),
+            Constraint::Length(7),
+        ],
+    )
+    .header(
+        Row::new(["start", "app_id", "alias", "played", ""])
+            .style(Style::new().add_modifier(Modifier::BOLD)),
+    )
+    .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+    .block(Block::bordered().title(format!(" Sessions ({}) ", dashboard.sessions.len())));
+    frame.render_stateful_widget(table, sessions_area, state);
+
+    frame.render_widget(
+        Paragraph::new("q quit  ↑/↓ scroll  PgUp/PgDn page  r refresh"),
+        help_area,
+    );
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time ne tuda")
+        .as_secs()
+}
+
+fn event_loop(
+    terminal: &mut DefaultTerminal,
+    conn: &Connection,
+    socket: Option<&Path>,
+    refresh: Duration,
+) -> io::Result<()> {
+    let mut state = TableState::default().with_selected(0);
+    let mut dashboard = load(conn, socket, now(), db::local_offset).map_err(io::Error::other)?;
+    let mut refreshed = Instant::now();
+
+    loop {
+        terminal.draw(|frame| draw(frame, &dashboard, &mut state))?;
+
+        let timeout = refresh.saturating_sub(refreshed.elapsed());
+        let mut reload = timeout.is_zero();
+        if event::poll(timeout)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                let page = terminal.size()?.height.saturating_sub(12).max(1);
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Char('r') => reload = true,
+                    KeyCode::Down | KeyCode::Char('j') => state.scroll_down_by(1),
+                    KeyCode::Up | KeyCode::Char('k') => state.scroll_up_by(1),
+                    KeyCode::PageDown => state.scroll_down_by(page),
+                    KeyCode::PageUp => state.scroll_up_by(page),
+                    KeyCode::Home => state.select_first(),
+                    KeyCode::End => state.select_last(),
+                    _ => {}
+                }
+            }
+        }
+
+        if reload {
+            dashboard = load(conn, socket, now(), db::local_offset).map_err(io::Error::other)?;
+            refreshed = Instant::now();
+        }
+    }
+}
+
+/// Shows the dashboard until the user quits, refreshing every `refresh`.
+pub fn run(conn: &Connection, socket: Option<&Path>, refresh: Duration) -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, conn, socket, refresh);
+    ratatui::restore();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::UNKNOWN_USER_ID;
+
+    #[test]
+    fn dashboard() {
+        let conn = DeckDB::open(":memory:").unwrap();
+        let now = 10 * 86400 + 12 * 3600;
+        sessions::add_session(&conn, 10, UNKNOWN_USER_ID, now - 7200, now - 5400, now).unwrap();
+        sessions::add_session(
+            &conn,
+            20,
+            UNKNOWN_USER_ID,
+            now - 86400 * 2,
+            now - 86400 * 2 + 600,
+            now,
+        )
+        .unwrap();
+        conn.execute_batch(
+            "update objects set alias = 'Hades' where app_id = 10; \
+            update timeline set utc_offset = 0;",
+        )
+        .unwrap();
+        DeckDB::get_object_id(&conn, 30).unwrap();
+        conn.execute(
+            "insert into events (timestamp, object_id, event_type) \
+                select ?1, object_id, ?2 from objects where app_id = 30",
+            (now - 60, db::EventType::Started as u32),
+        )
+        .unwrap();
+
+        let dashboard = load(&conn, None, now, |_| 0).unwrap();
+        assert!(!dashboard.live);
+        assert_eq!(
+            dashboard
+                .running
+                .iter()
+                .map(|app| (app.app_id, app.session_secs))
+                .collect::<Vec<_>>(),
+            vec![(30, 60)]
+        );
+        assert_eq!(dashboard.today_secs, 1800);
+        assert_eq!(dashboard.week_secs, 2400);
+        assert_eq!(dashboard.hourly[21..], [1800, 0, 0]);
+        // At 5:30 ahead the hour bucket covers half of two local hours.
+        conn.execute("update timeline set utc_offset = 19800", ())
+            .unwrap();
+        let dashboard = load(&conn, None, now, |_| 19800).unwrap();
+        assert_eq!(dashboard.hourly[21..], [900, 900, 0]);
+        assert_eq!(
+            dashboard
+                .sessions
+                .iter()
+                .map(|session| (session.app_id, session.alias.as_deref()))
+                .collect::<Vec<_>>(),
+            vec![(30, None), (10, Some("Hades")), (20, None)]
+        );
+    }
+}